@@ -1,6 +1,36 @@
+fn felinet_service() {
+    let post_packet = tonic_build::manual::Method::builder()
+        .name("post_packet")
+        .route_name("PostPacket")
+        .input_type("super::PacketIn")
+        .output_type("super::Empty")
+        .codec_path("tonic::codec::ProstCodec")
+        .build();
+
+    let get_packets = tonic_build::manual::Method::builder()
+        .name("get_packets")
+        .route_name("GetPackets")
+        .input_type("super::PacketRequest")
+        .output_type("super::PacketOut")
+        .codec_path("tonic::codec::ProstCodec")
+        .server_streaming()
+        .build();
+
+    let service = tonic_build::manual::Service::builder()
+        .name("Handler")
+        .package("felinet")
+        .method(post_packet)
+        .method(get_packets)
+        .build();
+
+    tonic_build::manual::Builder::new().compile(&[service]);
+}
+
 fn main() {
     println!("cargo:rerun-if-changed=templates/*");
 
+    felinet_service();
+
     let dir: String = std::env::var("CARGO_MANIFEST_DIR").unwrap();
     let input = format!("{dir}/style.css");
     let output = format!("{dir}/static/style.css");
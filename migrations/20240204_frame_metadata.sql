@@ -0,0 +1,4 @@
+ALTER TABLE frames_received ADD COLUMN rssi REAL;
+ALTER TABLE frames_received ADD COLUMN direction TEXT NOT NULL DEFAULT 'rx';
+ALTER TABLE frames_received ADD COLUMN freq INTEGER;
+ALTER TABLE frames_received ADD COLUMN decode_status TEXT NOT NULL DEFAULT 'ok';
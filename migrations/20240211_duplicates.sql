@@ -0,0 +1 @@
+ALTER TABLE frames_received ADD COLUMN duplicate_of INTEGER REFERENCES frames_received(id);
@@ -0,0 +1 @@
+ALTER TABLE frames_received ADD COLUMN decode_error TEXT;
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS transmissions
+(
+  id          INTEGER NOT NULL PRIMARY KEY,
+  queued_at   INTEGER NOT NULL,
+  updated_at  INTEGER NOT NULL,
+  source      TEXT NOT NULL,
+  state       TEXT NOT NULL,
+  error       TEXT,
+  content     BLOB,
+  frame_id    INTEGER REFERENCES frames_received(id)
+);
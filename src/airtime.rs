@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::config::AirtimeConfig;
+use crate::db::TxSource;
+
+mod estimate;
+pub use estimate::airtime;
+
+// Limits apply to the airtime of the last hour
+const WINDOW : Duration = Duration::from_secs(3600);
+
+const SOURCES : [TxSource; 6] = [
+    TxSource::Ui,
+    TxSource::Beacon,
+    TxSource::Digipeater,
+    TxSource::Felinet,
+    TxSource::Tunnel,
+    TxSource::Kiss,
+];
+
+// Hourly quota of the source, if it has one
+pub fn quota(conf: &AirtimeConfig, source: TxSource) -> Option<Duration> {
+    let seconds = match source {
+        TxSource::Ui => conf.ui_seconds_per_hour,
+        TxSource::Beacon => conf.beacon_seconds_per_hour,
+        TxSource::Digipeater => conf.digipeater_seconds_per_hour,
+        TxSource::Felinet => conf.felinet_seconds_per_hour,
+        TxSource::Tunnel => conf.tunnel_seconds_per_hour,
+        TxSource::Kiss => conf.kiss_seconds_per_hour,
+    };
+    seconds.map(|s| Duration::from_secs(s.into()))
+}
+
+pub enum Decision {
+    // The airtime was reserved
+    Allow,
+    // The airtime was reserved after this delay, the frame must be held until then
+    Delay(Duration),
+    // Not enough airtime within the maximum delay
+    Deny,
+}
+
+// Airtime used and limits, shown on the dashboard
+pub struct Usage {
+    pub source : Option<TxSource>,
+    pub used : Duration,
+    pub limit : Option<Duration>,
+}
+
+// Airtime of a queued or transmitted frame
+struct Reservation {
+    // When the frame may be sent
+    at : Instant,
+    id : i64,
+    source : TxSource,
+    airtime : Duration,
+}
+
+/* Accounts for the airtime of the frames handed to the radio during the
+ * last hour, sorted by the time they may be sent. Frames that are dropped,
+ * cancelled or fail to be sent must be released, so that they do not count. */
+#[derive(Default)]
+pub struct Limiter {
+    history : VecDeque<Reservation>,
+    pub num_delayed : u64,
+    pub num_dropped : u64,
+}
+
+impl Limiter {
+    // Frames of the last hour, of the source if given
+    fn recent(&self, source: Option<TxSource>, now: Instant) -> impl Iterator<Item = &Reservation> {
+        self.history.iter()
+            .filter(move |r| now.saturating_duration_since(r.at) < WINDOW)
+            .filter(move |r| source.is_none() || source == Some(r.source))
+    }
+
+    fn used(&self, source: Option<TxSource>, now: Instant) -> Duration {
+        self.recent(source, now).map(|r| r.airtime).sum()
+    }
+
+    // Time until the airtime fits in the limit, None if it never will
+    fn wait_for(&self, source: Option<TxSource>, airtime: Duration, limit: Duration, now: Instant) -> Option<Duration> {
+        if airtime > limit {
+            return None;
+        }
+
+        let mut excess = (self.used(source, now) + airtime).saturating_sub(limit);
+        if excess.is_zero() {
+            return Some(Duration::ZERO);
+        }
+
+        for r in self.recent(source, now) {
+            excess = excess.saturating_sub(r.airtime);
+            if excess.is_zero() {
+                return Some((r.at + WINDOW).saturating_duration_since(now));
+            }
+        }
+
+        Some(WINDOW)
+    }
+
+    // Reserve the airtime of a frame, as soon as the limits allow it
+    pub fn request(&mut self, conf: &AirtimeConfig, id: i64, source: TxSource, airtime: Duration) -> Decision {
+        let now = Instant::now();
+        while self.history.front().is_some_and(|r| now.saturating_duration_since(r.at) >= WINDOW) {
+            self.history.pop_front();
+        }
+
+        let wait = if conf.enabled {
+            let total = self.wait_for(None, airtime, Duration::from_secs(conf.max_seconds_per_hour.into()), now);
+            let own = match quota(conf, source) {
+                Some(q) => self.wait_for(Some(source), airtime, q, now),
+                None => Some(Duration::ZERO),
+            };
+
+            match (total, own) {
+                (Some(a), Some(b)) => a.max(b),
+                _ => Duration::MAX,
+            }
+        }
+        else {
+            Duration::ZERO
+        };
+
+        if wait > Duration::from_secs(conf.max_delay_seconds.into()) {
+            self.num_dropped += 1;
+            return Decision::Deny;
+        }
+
+        let at = now + wait;
+        let pos = self.history.partition_point(|r| r.at <= at);
+        self.history.insert(pos, Reservation { at, id, source, airtime });
+
+        if wait.is_zero() {
+            Decision::Allow
+        }
+        else {
+            self.num_delayed += 1;
+            Decision::Delay(wait)
+        }
+    }
+
+    // Give back the airtime of a frame that was not transmitted
+    pub fn release(&mut self, id: i64) {
+        self.history.retain(|r| r.id != id);
+    }
+
+    // Total usage first, then the usage of every source
+    pub fn usage(&self, conf: &AirtimeConfig) -> Vec<Usage> {
+        let now = Instant::now();
+        let total = Usage {
+            source: None,
+            used: self.used(None, now),
+            limit: conf.enabled.then(|| Duration::from_secs(conf.max_seconds_per_hour.into())),
+        };
+
+        std::iter::once(total)
+            .chain(SOURCES.iter().map(|&source| Usage {
+                source: Some(source),
+                used: self.used(Some(source), now),
+                limit: quota(conf, source).filter(|_| conf.enabled),
+            }))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn one_second_per_hour() -> AirtimeConfig {
+        AirtimeConfig {
+            enabled: true,
+            max_seconds_per_hour: 1,
+            max_delay_seconds: 0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn denies_over_the_limit() {
+        let conf = one_second_per_hour();
+        let mut limiter = Limiter::default();
+        assert!(matches!(limiter.request(&conf, 1, TxSource::Ui, airtime(1000)), Decision::Allow));
+        assert!(matches!(limiter.request(&conf, 2, TxSource::Ui, airtime(1000)), Decision::Deny));
+        assert_eq!(limiter.num_dropped, 1);
+    }
+
+    #[test]
+    fn released_airtime_is_available_again() {
+        let conf = one_second_per_hour();
+        let mut limiter = Limiter::default();
+        assert!(matches!(limiter.request(&conf, 1, TxSource::Ui, airtime(1000)), Decision::Allow));
+
+        limiter.release(1);
+        assert!(limiter.usage(&conf)[0].used.is_zero());
+        assert!(matches!(limiter.request(&conf, 2, TxSource::Ui, airtime(1000)), Decision::Allow));
+    }
+}
@@ -0,0 +1,15 @@
+// Airtime estimate of CATS frames.
+// Also used by the fake-radio tool, which simulates a shared channel.
+use std::time::Duration;
+
+/* CATS frames are sent at 9600 bit/s, after a preamble and a sync word.
+ * Ramp-up of the PA and similar delays are not accounted for. */
+const BITRATE : f64 = 9600.0;
+const PREAMBLE_LEN : usize = 4;
+const SYNC_WORD_LEN : usize = 4;
+
+// Estimated time on the air of a fully encoded frame, length prefix included
+pub fn airtime(frame_len: usize) -> Duration {
+    let bits = (PREAMBLE_LEN + SYNC_WORD_LEN + frame_len) * 8;
+    Duration::from_secs_f64(bits as f64 / BITRATE)
+}
@@ -0,0 +1,123 @@
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Context};
+use half::f16;
+use log::{debug, info, warn};
+
+use ham_cats::{
+    whisker::{Gps, Identification, NodeInfoBuilder, Route},
+};
+
+use crate::{config, db, framing, radio::MAX_PACKET_LEN, transmit, SharedState};
+
+fn build_beacon(config: &config::Config, uptime_seconds: u32) -> anyhow::Result<Vec<u8>> {
+    let beacon = &config.beacon;
+
+    let mut buf = [0; MAX_PACKET_LEN];
+    let mut pkt = ham_cats::packet::Packet::new(&mut buf);
+    pkt.add_identification(
+        Identification::new(&config.callsign, config.ssid, config.icon)
+            .context("Invalid identification")?,
+    )
+    .map_err(|e| anyhow!("Could not add identification to packet: {e}"))?;
+
+    if let (Some(latitude), Some(longitude)) = (beacon.latitude, beacon.longitude) {
+        let altitude = f16::from_f64(beacon.altitude.unwrap_or(0.0));
+        let max_error = 0;
+        let heading = 0.0;
+        let speed = f16::ZERO;
+
+        pkt.add_gps(Gps::new(latitude, longitude, altitude, max_error, heading, speed))
+            .map_err(|e| anyhow!("Could not add GPS to packet: {e}"))?;
+    }
+
+    if let Some(comment) = &beacon.comment {
+        pkt.add_comment(comment)
+            .map_err(|e| anyhow!("Could not add comment to packet: {e}"))?;
+    }
+
+    pkt.add_route(Route::new(beacon.max_hops))
+        .map_err(|e| anyhow!("Could not add route to packet: {e}"))?;
+
+    let mut node_info = NodeInfoBuilder::default().uptime(uptime_seconds);
+    if let Some(antenna_height) = beacon.antenna_height {
+        node_info = node_info.antenna_height(antenna_height);
+    }
+    if let Some(antenna_gain) = beacon.antenna_gain {
+        node_info = node_info.antenna_gain(antenna_gain.into());
+    }
+    if let Some(tx_power) = beacon.tx_power {
+        node_info = node_info.tx_power(tx_power.into());
+    }
+    pkt.add_node_info(node_info.build())
+        .map_err(|e| anyhow!("Could not add node info to packet: {e}"))?;
+
+    framing::encode_packet(pkt)
+}
+
+async fn wait_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/* Transmit a beacon every period. The configuration is followed as it changes
+ * in the settings page, the first beacon is sent as soon as the beacon is enabled. */
+pub async fn run(shared_state: SharedState) {
+    let (mut conf_receive, start_time) = {
+        let s = shared_state.lock().unwrap();
+        (s.conf.subscribe(), s.start_time)
+    };
+
+    let mut last_beacon : Option<Instant> = None;
+    let mut previous_period : config::DurationSeconds = 0;
+
+    loop {
+        let conf = conf_receive.borrow_and_update().clone();
+
+        let period_seconds = conf.beacon.period_seconds;
+        if period_seconds != previous_period {
+            match (previous_period, period_seconds) {
+                (_, 0) => info!("Beacon disabled"),
+                (0, _) => info!("Beacon enabled with period {period_seconds}s"),
+                _ => info!("Beacon period changed to {period_seconds}s"),
+            }
+            previous_period = period_seconds;
+        }
+
+        let next_beacon = match (period_seconds, last_beacon) {
+            (0, _) => None,
+            (_, Some(sent_at)) => Some(sent_at + Duration::from_secs(period_seconds.into())),
+            (_, None) => Some(Instant::now()),
+        };
+
+        if period_seconds == 0 {
+            last_beacon = None;
+        }
+
+        tokio::select! {
+            _ = wait_until(next_beacon) => {
+                last_beacon = Some(Instant::now());
+
+                let uptime_seconds = (chrono::Utc::now() - start_time).num_seconds()
+                    .try_into()
+                    .unwrap_or(u32::MAX);
+
+                match build_beacon(&conf, uptime_seconds) {
+                    Ok(data) => {
+                        debug!("Sending beacon of {} bytes", data.len());
+
+                        if let Err(e) = transmit::queue(&shared_state, data, db::TxSource::Beacon).await {
+                            warn!("Failed to queue beacon: {e}");
+                        }
+                    },
+                    Err(e) => warn!("Failed to prepare beacon: {e}"),
+                }
+            },
+            changed = conf_receive.changed() => if changed.is_err() {
+                warn!("Beacon: configuration channel closed, stopping");
+                return;
+            },
+        }
+    }
+}
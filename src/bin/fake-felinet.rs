@@ -0,0 +1,120 @@
+use std::pin::Pin;
+use anyhow::{anyhow, Context};
+use futures::Stream;
+use ham_cats::{
+    buffer::Buffer,
+    whisker::Identification,
+};
+use tokio::sync::broadcast;
+use tonic::{transport::Server, Request, Response, Status};
+
+#[path = "../felinet_proto.rs"]
+mod felinet_proto;
+
+use felinet_proto::{
+    handler_server::{Handler, HandlerServer},
+    Empty, PacketIn, PacketOut, PacketRequest,
+};
+
+const MAX_PACKET_LEN : usize = 8191;
+
+fn build_example_packet(comment: &str) -> anyhow::Result<Vec<u8>> {
+    let mut buf = [0; MAX_PACKET_LEN];
+    let mut pkt = ham_cats::packet::Packet::new(&mut buf);
+    pkt.add_identification(
+        Identification::new("EX4MPLE", 1, 0)
+            .context("Invalid identification")?,
+    )
+    .map_err(|e| anyhow!("Could not add identification to packet: {e}"))?;
+
+    pkt.add_comment(comment)
+        .map_err(|e| anyhow!("Could not add comment to packet: {e}"))?;
+
+    let data = pkt.semi_encode()
+        .map_err(|(e, _)| anyhow!("Could not encode packet: {e}"))?;
+
+    Ok(data.to_vec())
+}
+
+fn print_packet(raw: &[u8]) {
+    let mut buf = [0; MAX_PACKET_LEN];
+    let mut data = Buffer::new_empty(&mut buf);
+    if data.try_extend_from_slice(raw).is_err() {
+        eprintln!(" Packet too long");
+        return;
+    }
+
+    match ham_cats::packet::Packet::semi_decode(data) {
+        Ok(packet) => {
+            if let Some(ident) = packet.identification() {
+                eprintln!(" Ident {}-{}", ident.callsign, ident.ssid);
+            }
+
+            let mut comment = [0; 1024];
+            if let Ok(c) = packet.comment(&mut comment) {
+                eprintln!(" Comment {}", c);
+            }
+        },
+        Err(e) => {
+            eprintln!(" Cannot decode {:?}", e);
+        }
+    }
+}
+
+struct FakeFelinet {
+    downlink: broadcast::Sender<Vec<u8>>,
+}
+
+#[tonic::async_trait]
+impl Handler for FakeFelinet {
+    async fn post_packet(&self, request: Request<PacketIn>) -> Result<Response<Empty>, Status> {
+        let packet = request.into_inner();
+        eprintln!("Packet of length {} posted", packet.raw.len());
+        print_packet(&packet.raw);
+        Ok(Response::new(Empty {}))
+    }
+
+    type GetPacketsStream = Pin<Box<dyn Stream<Item = Result<PacketOut, Status>> + Send>>;
+
+    async fn get_packets(&self, _request: Request<PacketRequest>) -> Result<Response<Self::GetPacketsStream>, Status> {
+        eprintln!("Client subscribed to packets");
+        let mut rx = self.downlink.subscribe();
+        let stream = async_stream::stream! {
+            while let Ok(raw) = rx.recv().await {
+                yield Ok(PacketOut { raw });
+            }
+        };
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let addr = std::env::args().nth(1)
+        .unwrap_or("127.0.0.1:50051".to_owned())
+        .parse()
+        .context("Invalid listen address")?;
+
+    let (downlink, _) = broadcast::channel(16);
+    let downlink_stdin = downlink.clone();
+
+    std::thread::spawn(move || {
+        eprintln!("Write a comment and press ENTER to send it to subscribed nodes. Ctrl-C to stop");
+        let mut stdin_lines = std::io::stdin().lines();
+        while let Some(Ok(line)) = stdin_lines.next() {
+            eprintln!("Sending with comment = {}", line);
+            let packet = build_example_packet(&line).unwrap();
+            if downlink_stdin.send(packet).is_err() {
+                eprintln!("No node subscribed");
+            }
+        }
+    });
+
+    eprintln!("FELINET stand-in listening on http://{addr}");
+    Server::builder()
+        .add_service(HandlerServer::new(FakeFelinet { downlink }))
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
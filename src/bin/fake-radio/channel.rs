@@ -0,0 +1,228 @@
+/* Simulated radio channel between several nodes using the UDP radio backend.
+ * Every frame sent by a node is delivered to the nodes it has a link with,
+ * after the propagation delay and its airtime, unless it is lost or collides
+ * with another frame at the receiver. */
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{bail, Context};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::{airtime::airtime, framing};
+
+// A frame at least this much stronger than another one survives their collision
+const CAPTURE_DB : f64 = 6.0;
+
+#[derive(Deserialize)]
+struct NodeConfig {
+    name : String,
+    // Where the node receives, its udp_rx_address
+    rx_address : SocketAddr,
+    // Where the node transmits, its udp_tx_address
+    tx_address : SocketAddr,
+}
+
+#[derive(Deserialize)]
+struct LinkConfig {
+    a : String,
+    b : String,
+    rssi : f64, // dBm
+    #[serde(default)]
+    delay_ms : u64,
+    #[serde(default)]
+    loss_percent : f64,
+    // Only a hears b if set
+    #[serde(default)]
+    one_way : bool,
+}
+
+#[derive(Deserialize)]
+struct ChannelConfig {
+    #[serde(rename = "node")]
+    nodes : Vec<NodeConfig>,
+    #[serde(default, rename = "link")]
+    links : Vec<LinkConfig>,
+}
+
+struct Link {
+    to : usize,
+    rssi : f64,
+    delay : Duration,
+    loss_percent : f64,
+}
+
+struct Reception {
+    id : u64,
+    from : usize,
+    start : Instant,
+    end : Instant,
+    rssi : f64,
+    corrupted : bool,
+}
+
+#[derive(Default)]
+struct NodeState {
+    // Frames on their way to the node
+    receptions : Vec<Reception>,
+    transmitting_until : Option<Instant>,
+}
+
+struct Channel {
+    names : Vec<String>,
+    rx_addresses : Vec<SocketAddr>,
+    // Links leaving each node
+    links : Vec<Vec<Link>>,
+    state : Mutex<(u64, Vec<NodeState>)>,
+    socket : UdpSocket,
+}
+
+impl Channel {
+    fn new(conf: ChannelConfig, socket: UdpSocket) -> anyhow::Result<Self> {
+        let names : Vec<String> = conf.nodes.iter().map(|n| n.name.clone()).collect();
+        let index = |name: &str| names.iter().position(|n| n == name)
+            .with_context(|| format!("Unknown node '{name}' in link"));
+
+        let mut links : Vec<Vec<Link>> = conf.nodes.iter().map(|_| Vec::new()).collect();
+        for l in &conf.links {
+            let (a, b) = (index(&l.a)?, index(&l.b)?);
+            if a == b {
+                bail!("Link from node '{}' to itself", l.a);
+            }
+
+            let link = |to| Link { to, rssi: l.rssi, delay: Duration::from_millis(l.delay_ms), loss_percent: l.loss_percent };
+            links[b].push(link(a));
+            if !l.one_way {
+                links[a].push(link(b));
+            }
+        }
+
+        let states = conf.nodes.iter().map(|_| NodeState::default()).collect();
+        Ok(Self {
+            rx_addresses: conf.nodes.iter().map(|n| n.rx_address).collect(),
+            names,
+            links,
+            state: Mutex::new((0, states)),
+            socket,
+        })
+    }
+
+    // Put a frame sent by a node on the channel, returns the receptions to complete
+    fn transmit(&self, from: usize, len: usize) -> Vec<(u64, usize, Instant)> {
+        let now = Instant::now();
+        let airtime = airtime(len);
+        let mut deliveries = Vec::new();
+
+        let mut guard = self.state.lock().unwrap();
+        let (next_id, nodes) = &mut *guard;
+
+        // Half duplex, the sender misses what it was receiving
+        nodes[from].transmitting_until = Some(now + airtime);
+        for r in nodes[from].receptions.iter_mut().filter(|r| r.start < now + airtime) {
+            r.corrupted = true;
+        }
+
+        for link in &self.links[from] {
+            if thread_rng().gen_range(0.0..100.0) < link.loss_percent {
+                eprintln!("{} -> {}: lost", self.names[from], self.names[link.to]);
+                continue;
+            }
+
+            let start = now + link.delay;
+            let end = start + airtime;
+            let receiver = &mut nodes[link.to];
+
+            let mut corrupted = receiver.transmitting_until.is_some_and(|t| t > start);
+            for other in receiver.receptions.iter_mut().filter(|r| r.start < end && start < r.end) {
+                if link.rssi - other.rssi < CAPTURE_DB {
+                    corrupted = true;
+                }
+                if other.rssi - link.rssi < CAPTURE_DB {
+                    other.corrupted = true;
+                }
+            }
+
+            *next_id += 1;
+            receiver.receptions.push(Reception { id: *next_id, from, start, end, rssi: link.rssi, corrupted });
+            deliveries.push((*next_id, link.to, end));
+        }
+
+        deliveries
+    }
+
+    // Hand the frame to the receiver once it was completely received
+    async fn deliver(&self, id: u64, to: usize, end: Instant, data: &[u8]) {
+        tokio::time::sleep_until(end).await;
+
+        let reception = {
+            let mut guard = self.state.lock().unwrap();
+            let receptions = &mut guard.1[to].receptions;
+            match receptions.iter().position(|r| r.id == id) {
+                Some(i) => receptions.remove(i),
+                None => return,
+            }
+        };
+
+        let from = &self.names[reception.from];
+        let name = &self.names[to];
+        if reception.corrupted {
+            eprintln!("{from} -> {name}: collision");
+            return;
+        }
+
+        let datagram = framing::encode_datagram(data, Some(reception.rssi));
+        match self.socket.send_to(&datagram, self.rx_addresses[to]).await {
+            Ok(_) => eprintln!("{from} -> {name}: {} bytes at {:.1} dBm", data.len(), reception.rssi),
+            Err(e) => eprintln!("{from} -> {name}: {e}"),
+        }
+    }
+}
+
+async fn receive_from_node(channel: Arc<Channel>, from: usize, socket: UdpSocket) -> anyhow::Result<()> {
+    let mut buf = [0; framing::MAX_DATAGRAM_LEN];
+    loop {
+        let (len, _) = socket.recv_from(&mut buf).await
+            .with_context(|| format!("receiving from node '{}'", channel.names[from]))?;
+        // Nodes send prefixed frames, and the RSSI is added at delivery
+        let frame = match framing::decode_datagram(&buf[..len]) {
+            Ok((frame, _)) => frame,
+            Err(e) => {
+                eprintln!("{}: invalid datagram: {e}", channel.names[from]);
+                continue;
+            },
+        };
+        let data = Arc::new(framing::add_length_prefix(frame)?);
+        for (id, to, end) in channel.transmit(from, data.len()) {
+            let channel = channel.clone();
+            let data = data.clone();
+            tokio::task::spawn(async move { channel.deliver(id, to, end, &data).await });
+        }
+    }
+}
+
+pub async fn run(config_file: &str) -> anyhow::Result<()> {
+    let conf : ChannelConfig = toml::from_str(&std::fs::read_to_string(config_file)?)
+        .context("parsing channel file")?;
+
+    let mut sockets = Vec::new();
+    for node in &conf.nodes {
+        let socket = UdpSocket::bind(node.tx_address).await
+            .with_context(|| format!("binding {} for node '{}'", node.tx_address, node.name))?;
+        sockets.push(socket);
+    }
+
+    let channel = Arc::new(Channel::new(conf, UdpSocket::bind("127.0.0.1:0").await?)?);
+    eprintln!("Simulating a channel between {}. Ctrl-C to stop", channel.names.join(", "));
+
+    let mut tasks = tokio::task::JoinSet::new();
+    for (from, socket) in sockets.into_iter().enumerate() {
+        tasks.spawn(receive_from_node(channel.clone(), from, socket));
+    }
+
+    match tasks.join_next().await {
+        Some(result) => result?,
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,179 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use anyhow::{anyhow, Context};
+use ham_cats::whisker::{Arbitrary, Identification, Gps};
+
+// Frames are only exchanged as datagrams here
+#[path = "../../framing.rs"]
+#[allow(dead_code)]
+mod framing;
+
+// Frames occupy the simulated channel as long as they would on the air
+#[path = "../../airtime/estimate.rs"]
+mod airtime;
+
+const MAX_PACKET_LEN : usize = 8191;
+
+fn build_example_packet(comment: &str) -> anyhow::Result<Vec<u8>> {
+    let callsign = "EX4MPLE";
+    let ssid = 0;
+    let icon = 0;
+
+    let mut buf = [0; MAX_PACKET_LEN];
+    let mut pkt = ham_cats::packet::Packet::new(&mut buf);
+    pkt.add_identification(
+        Identification::new(callsign, ssid, icon)
+            .context("Invalid identification")?,
+    )
+    .map_err(|e| anyhow!("Could not add identification to packet: {e}"))?;
+
+    pkt.add_comment(comment)
+        .map_err(|e| anyhow!("Could not add comment to packet: {e}"))?;
+
+    let latitude = 46.5;
+    let longitude = -6.2;
+    let altitude = 200u8.into();
+    let max_error = 1;
+    let heading = 120.0;
+    let speed = 1u8.into();
+
+    pkt.add_gps(Gps::new(
+        latitude,
+        longitude,
+        altitude,
+        max_error,
+        heading,
+        speed)
+    )
+    .map_err(|e| anyhow!("Could not add GPS to packet: {e}"))?;
+
+    pkt.add_arbitrary(Arbitrary::new(&[0xA5; 8]).unwrap())
+        .map_err(|e| anyhow!("Could not add arbitrary to packet: {e}"))?;
+
+    framing::encode_packet(pkt)
+}
+
+mod channel;
+mod scenario;
+
+const USAGE : &str = "Usage: fake-radio [--node-rx <address>] [--node-tx <address>]
+       fake-radio channel <channel.toml>
+       fake-radio scenario <scenario.toml|json>";
+
+// Addresses of a node with the default radio configuration
+const NODE_RX_ADDRESS : &str = "127.0.0.1:9073";
+const NODE_TX_ADDRESS : &str = "127.0.0.1:9074";
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args : Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(|a| a.as_str()).collect::<Vec<_>>().as_slice() {
+        ["channel", config_file] => channel::run(config_file).await,
+        ["scenario", script] => scenario::run(script).await,
+        options => {
+            let mut node_rx = NODE_RX_ADDRESS.parse()?;
+            let mut node_tx = NODE_TX_ADDRESS.parse()?;
+            for option in options.chunks(2) {
+                match option {
+                    ["--node-rx", address] => node_rx = address.parse().context("--node-rx")?,
+                    ["--node-tx", address] => node_tx = address.parse().context("--node-tx")?,
+                    _ => anyhow::bail!(USAGE),
+                }
+            }
+
+            tokio::task::spawn_blocking(move || interactive(node_rx, node_tx)).await??;
+            Ok(())
+        },
+    }
+}
+
+fn unspecified(address: SocketAddr, port: u16) -> SocketAddr {
+    match address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, port).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, port).into(),
+    }
+}
+
+// Bind the socket receiving what the node sends, joining the group of a multicast address
+fn bind_receiver(address: SocketAddr) -> std::io::Result<UdpSocket> {
+    if !address.ip().is_multicast() {
+        return UdpSocket::bind(address);
+    }
+
+    let socket = socket2::Socket::new(socket2::Domain::for_address(address), socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+    // The nodes of the host listen to the same group and port
+    socket.set_reuse_address(true)?;
+    socket.bind(&unspecified(address, address.port()).into())?;
+    match address.ip() {
+        IpAddr::V4(group) => socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?,
+        IpAddr::V6(group) => socket.join_multicast_v6(&group, 0)?,
+    }
+    Ok(socket.into())
+}
+
+// Inject frames typed on stdin into a node, and print what it transmits
+fn interactive(node_rx: SocketAddr, node_tx: SocketAddr) -> anyhow::Result<()> {
+    let receiver = bind_receiver(node_tx)
+        .with_context(|| format!("binding {node_tx}"))?;
+    let sock = UdpSocket::bind(unspecified(node_rx, 0))?;
+    // With a multicast group, our own frames come back and must be skipped
+    let own_port = sock.local_addr()?.port();
+    std::thread::spawn(move || receive_loop(receiver, own_port));
+
+    eprintln!("Receiving messages. Write a comment and press ENTER to send. Ctrl-C to stop");
+    let mut stdin_lines = std::io::stdin().lines();
+
+    while let Some(Ok(line)) = stdin_lines.next() {
+        eprintln!("Sending with comment = {}", line);
+
+        let packet = build_example_packet(&line).unwrap();
+        sock.send_to(&framing::encode_datagram(&packet, None), node_rx).unwrap();
+    }
+
+    Ok(())
+}
+
+fn receive_loop(sock: UdpSocket, own_port: u16) {
+    let mut data = [0; framing::MAX_DATAGRAM_LEN];
+    while let Ok((len, addr)) = sock.recv_from(&mut data) {
+        if addr.port() == own_port {
+            continue;
+        }
+        eprintln!("Packet of length {}", len);
+
+        let frame = match framing::decode_datagram(&data[..len]) {
+            Ok((frame, _)) => frame,
+            Err(e) => {
+                eprintln!(" Invalid datagram: {e}");
+                continue;
+            }
+        };
+
+        let mut buf = [0; MAX_PACKET_LEN];
+        match ham_cats::packet::Packet::fully_decode(frame, &mut buf) {
+            Ok(packet) => {
+                if let Some(ident) = packet.identification() {
+                    eprintln!(" Ident {}-{}", ident.callsign, ident.ssid);
+                }
+
+                for dest in packet.destination_iter() {
+                    eprintln!(" TO {}-{}", dest.callsign(), dest.ssid());
+                }
+
+                if let Some(gps) = packet.gps() {
+                    eprintln!(" GPS {} {}", gps.latitude(), gps.longitude());
+                }
+
+                let mut comment = [0; 1024];
+                if let Ok(c) = packet.comment(&mut comment) {
+                    eprintln!(" Comment {}", c);
+                }
+
+                eprintln!(" With {} Arbitrary whiskers", packet.arbitrary_iter().count());
+            },
+            Err(e) => {
+                eprintln!(" Cannot decode {:?}", e);
+            }
+        }
+    }
+}
+
@@ -0,0 +1,258 @@
+/* Traffic generator playing a scenario into a node using the UDP radio backend.
+ * The scenario describes stations, the whiskers of their packets and how often
+ * they transmit. Every station transmits independently, so the node receives a
+ * realistic mix of traffic. */
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context};
+use half::f16;
+use ham_cats::{
+    identity::Identity,
+    whisker::{Arbitrary, Destination, Gps, Identification, NodeInfoBuilder, PastHop, Route, Timestamp},
+};
+use serde::Deserialize;
+use tokio::net::UdpSocket;
+
+use crate::{framing, MAX_PACKET_LEN};
+
+fn default_target() -> SocketAddr {
+    "127.0.0.1:9073".parse().unwrap()
+}
+
+fn default_interval() -> f64 {
+    60.0
+}
+
+fn default_rssi() -> f64 {
+    -80.0
+}
+
+#[derive(Deserialize)]
+struct Hop {
+    callsign : String,
+    #[serde(default)]
+    ssid : u8,
+    // Only used for past hops
+    rssi : Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct RouteConfig {
+    max_hops : u8,
+    #[serde(default)]
+    past : Vec<Hop>,
+    #[serde(default)]
+    future : Vec<Hop>,
+}
+
+#[derive(Default, Deserialize)]
+struct NodeInfoConfig {
+    hardware_id : Option<u16>,
+    software_id : Option<u8>,
+    uptime : Option<u32>,
+    antenna_height : Option<u8>,
+    antenna_gain : Option<f64>,
+    tx_power : Option<f64>,
+    voltage : Option<f64>,
+    xcvr_temperature : Option<i8>,
+    battery_charge : Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct TrackPoint {
+    latitude : f64,
+    longitude : f64,
+    #[serde(default)]
+    altitude : f64,
+    #[serde(default)]
+    heading : f64,
+    #[serde(default)]
+    speed : f64,
+}
+
+#[derive(Deserialize)]
+struct StationConfig {
+    callsign : String,
+    #[serde(default)]
+    ssid : u8,
+    #[serde(default)]
+    icon : u16,
+    // Time of the first packet, from the start of the scenario
+    #[serde(default)]
+    start_seconds : f64,
+    #[serde(default = "default_interval")]
+    interval_seconds : f64,
+    // Both of the above, checked when loading
+    #[serde(skip)]
+    start : Duration,
+    #[serde(skip)]
+    interval : Duration,
+    // Number of packets, the station transmits until the end otherwise
+    count : Option<u64>,
+    // RSSI the node receives the station with
+    #[serde(default = "default_rssi")]
+    rssi : f64,
+    // {n} is replaced by the number of the packet, starting at 1
+    comment : Option<String>,
+    #[serde(default)]
+    timestamp : bool,
+    #[serde(default)]
+    destinations : Vec<Hop>,
+    route : Option<RouteConfig>,
+    node_info : Option<NodeInfoConfig>,
+    // Positions, one per packet. The station starts over at the end of the track
+    #[serde(default)]
+    track : Vec<TrackPoint>,
+    // Size of an arbitrary whisker filled with 0xA5
+    arbitrary_len : Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct Scenario {
+    // Where the node receives, its udp_rx_address
+    #[serde(default = "default_target")]
+    target : SocketAddr,
+    #[serde(rename = "station")]
+    stations : Vec<StationConfig>,
+}
+
+fn build_packet(station: &StationConfig, n: u64) -> anyhow::Result<Vec<u8>> {
+    let mut buf = [0; MAX_PACKET_LEN];
+    let mut pkt = ham_cats::packet::Packet::new(&mut buf);
+    pkt.add_identification(
+        Identification::new(&station.callsign, station.ssid, station.icon)
+            .context("Invalid identification")?,
+    )
+    .map_err(|e| anyhow!("Could not add identification to packet: {e}"))?;
+
+    if station.timestamp {
+        let now = chrono::Utc::now().timestamp() as u64;
+        pkt.add_timestamp(Timestamp::new(now).context("Invalid timestamp")?)
+            .map_err(|e| anyhow!("Could not add timestamp to packet: {e}"))?;
+    }
+
+    if !station.track.is_empty() {
+        let p = &station.track[((n - 1) % station.track.len() as u64) as usize];
+        let max_error = 1;
+        pkt.add_gps(Gps::new(
+            p.latitude,
+            p.longitude,
+            f16::from_f64(p.altitude),
+            max_error,
+            p.heading,
+            f16::from_f64(p.speed))
+        )
+        .map_err(|e| anyhow!("Could not add GPS to packet: {e}"))?;
+    }
+
+    if let Some(comment) = &station.comment {
+        pkt.add_comment(&comment.replace("{n}", &n.to_string()))
+            .map_err(|e| anyhow!("Could not add comment to packet: {e}"))?;
+    }
+
+    for dest in &station.destinations {
+        pkt.add_destination(
+            Destination::new(false, 0, &dest.callsign, dest.ssid)
+                .context("Invalid destination")?,
+        )
+        .map_err(|e| anyhow!("Could not add destination to packet: {e}"))?;
+    }
+
+    if let Some(r) = &station.route {
+        let mut route = Route::new(r.max_hops);
+        for hop in &r.past {
+            route.push_past(PastHop::new(Identity::new(&hop.callsign, hop.ssid), hop.rssi))
+                .context("Route too long")?;
+        }
+        for hop in &r.future {
+            route.push_future(Identity::new(&hop.callsign, hop.ssid))
+                .context("Route too long")?;
+        }
+        pkt.add_route(route)
+            .map_err(|e| anyhow!("Could not add route to packet: {e}"))?;
+    }
+
+    if let Some(i) = &station.node_info {
+        let mut node_info = NodeInfoBuilder::default();
+        if let Some(v) = i.hardware_id { node_info = node_info.hardware_id(v); }
+        if let Some(v) = i.software_id { node_info = node_info.software_id(v); }
+        if let Some(v) = i.uptime { node_info = node_info.uptime(v); }
+        if let Some(v) = i.antenna_height { node_info = node_info.antenna_height(v); }
+        if let Some(v) = i.antenna_gain { node_info = node_info.antenna_gain(v); }
+        if let Some(v) = i.tx_power { node_info = node_info.tx_power(v); }
+        if let Some(v) = i.voltage { node_info = node_info.voltage(v); }
+        if let Some(v) = i.xcvr_temperature { node_info = node_info.xcvr_temperature(v); }
+        if let Some(v) = i.battery_charge { node_info = node_info.battery_charge(v); }
+        pkt.add_node_info(node_info.build())
+            .map_err(|e| anyhow!("Could not add node info to packet: {e}"))?;
+    }
+
+    if let Some(len) = station.arbitrary_len {
+        pkt.add_arbitrary(Arbitrary::new(&vec![0xA5; len]).context("Arbitrary whisker too long")?)
+            .map_err(|e| anyhow!("Could not add arbitrary to packet: {e}"))?;
+    }
+
+    framing::encode_packet(pkt)
+}
+
+async fn play_station(station: StationConfig, socket: std::sync::Arc<UdpSocket>, target: SocketAddr) -> anyhow::Result<()> {
+    let name = format!("{}-{}", station.callsign, station.ssid);
+    tokio::time::sleep(station.start).await;
+
+    let mut interval = tokio::time::interval(station.interval);
+    let mut n = 0;
+    while station.count.map_or(true, |count| n < count) {
+        interval.tick().await;
+        n += 1;
+
+        let packet = build_packet(&station, n)
+            .with_context(|| format!("building packet {n} of {name}"))?;
+        socket.send_to(&framing::encode_datagram(&packet, Some(station.rssi)), target).await
+            .with_context(|| format!("sending packet {n} of {name}"))?;
+        eprintln!("{name}: packet {n}, {} bytes", packet.len());
+    }
+
+    Ok(())
+}
+
+fn load(script: &str) -> anyhow::Result<Scenario> {
+    let contents = std::fs::read_to_string(script)?;
+    let mut scenario : Scenario = match Path::new(script).extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&contents).context("parsing scenario")?,
+        _ => toml::from_str(&contents).context("parsing scenario")?,
+    };
+
+    for station in &mut scenario.stations {
+        // Negative, infinite, NaN and overly large values have no duration
+        station.start = Duration::try_from_secs_f64(station.start_seconds)
+            .with_context(|| format!("Invalid start_seconds for station {}", station.callsign))?;
+        station.interval = Duration::try_from_secs_f64(station.interval_seconds)
+            .with_context(|| format!("Invalid interval_seconds for station {}", station.callsign))?;
+        // Also catches intervals too short to be represented
+        if station.interval.is_zero() {
+            bail!("Invalid interval_seconds for station {}", station.callsign);
+        }
+    }
+
+    Ok(scenario)
+}
+
+pub async fn run(script: &str) -> anyhow::Result<()> {
+    let scenario = load(script)?;
+    let socket = std::sync::Arc::new(UdpSocket::bind(crate::unspecified(scenario.target, 0)).await?);
+    eprintln!("Playing {} stations into {}. Ctrl-C to stop", scenario.stations.len(), scenario.target);
+
+    let mut tasks = tokio::task::JoinSet::new();
+    for station in scenario.stations {
+        tasks.spawn(play_station(station, socket.clone(), scenario.target));
+    }
+
+    // Stations without a count keep the scenario running
+    while let Some(result) = tasks.join_next().await {
+        result??;
+    }
+
+    eprintln!("Scenario finished");
+    Ok(())
+}
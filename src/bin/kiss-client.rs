@@ -0,0 +1,86 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use anyhow::{anyhow, Context};
+use ham_cats::whisker::Identification;
+
+// Only the prefix handling is needed here
+#[path = "../framing.rs"]
+#[allow(dead_code)]
+mod framing;
+#[path = "../kiss_codec.rs"]
+mod kiss_codec;
+
+const MAX_PACKET_LEN : usize = 8191;
+
+fn build_example_packet(comment: &str) -> anyhow::Result<Vec<u8>> {
+    let mut buf = [0; MAX_PACKET_LEN];
+    let mut pkt = ham_cats::packet::Packet::new(&mut buf);
+    pkt.add_identification(
+        Identification::new("EX4MPLE", 2, 0)
+            .context("Invalid identification")?,
+    )
+    .map_err(|e| anyhow!("Could not add identification to packet: {e}"))?;
+
+    pkt.add_comment(comment)
+        .map_err(|e| anyhow!("Could not add comment to packet: {e}"))?;
+
+    // KISS carries the frame without the length prefix
+    let data = framing::encode_packet(pkt)?;
+    Ok(framing::strip_length_prefix(&data)?.to_vec())
+}
+
+fn print_frame(frame: &[u8]) {
+    let mut buf = [0; MAX_PACKET_LEN];
+    match ham_cats::packet::Packet::fully_decode(frame, &mut buf) {
+        Ok(packet) => {
+            if let Some(ident) = packet.identification() {
+                eprintln!(" Ident {}-{}", ident.callsign, ident.ssid);
+            }
+
+            let mut comment = [0; 1024];
+            if let Ok(c) = packet.comment(&mut comment) {
+                eprintln!(" Comment {}", c);
+            }
+        },
+        Err(e) => {
+            eprintln!(" Cannot decode {:?}", e);
+        }
+    }
+}
+
+fn receive_loop(mut stream: TcpStream) {
+    let mut decoder = kiss_codec::Decoder::new(MAX_PACKET_LEN + 1);
+    let mut buf = [0; 1024];
+    while let Ok(len) = stream.read(&mut buf) {
+        if len == 0 {
+            eprintln!("Connection closed");
+            std::process::exit(0);
+        }
+
+        for kiss_frame in decoder.push(&buf[..len]) {
+            eprintln!("Frame of length {}", kiss_frame.len() - 1);
+            print_frame(&kiss_frame[1..]);
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let addr = std::env::args().nth(1)
+        .unwrap_or("127.0.0.1:8001".to_owned());
+
+    let mut stream = TcpStream::connect(&addr)
+        .with_context(|| format!("Could not connect to {addr}"))?;
+
+    let receive_stream = stream.try_clone()?;
+    std::thread::spawn(move || receive_loop(receive_stream));
+
+    eprintln!("Connected to {addr}. Write a comment and press ENTER to send. Ctrl-C to stop");
+    let mut stdin_lines = std::io::stdin().lines();
+    while let Some(Ok(line)) = stdin_lines.next() {
+        eprintln!("Sending with comment = {}", line);
+        let frame = build_example_packet(&line)?;
+        stream.write_all(&kiss_codec::encode(&frame))?;
+    }
+
+    Ok(())
+}
@@ -1,23 +1,27 @@
 use std::fs;
-use anyhow::Context;
+use anyhow::{bail, Context};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct FelinetConfig {
     pub enabled: bool,
     pub address: String,
+    // Transmit the packets received from FELINET
+    #[serde(default)]
+    pub downlink: bool,
 }
 
 impl Default for FelinetConfig {
     fn default() -> Self {
         FelinetConfig {
             enabled: false,
-            address: "https://felinet.cats.radio".to_owned()
+            address: "https://felinet.cats.radio".to_owned(),
+            downlink: false,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct TunnelConfig {
     pub enabled: bool,
     pub local_ip: String,
@@ -36,7 +40,7 @@ impl Default for TunnelConfig {
 
 pub(crate) type DurationSeconds = u32;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct BeaconConfig {
     // A period of zero means beaconing is disabled
     pub period_seconds: DurationSeconds,
@@ -67,7 +71,292 @@ impl Default for BeaconConfig {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DigipeaterConfig {
+    pub enabled: bool,
+    // Packets whose route allows more hops are not repeated
+    pub max_hops: u8,
+    // If not empty, only packets from these callsigns are repeated.
+    // Entries are either CALLSIGN or CALLSIGN-SSID
+    #[serde(default)]
+    pub only_callsigns: Vec<String>,
+    // Packets from these callsigns are never repeated
+    #[serde(default)]
+    pub ignore_callsigns: Vec<String>,
+}
+
+impl Default for DigipeaterConfig {
+    fn default() -> Self {
+        DigipeaterConfig {
+            enabled: false,
+            max_hops: 3,
+            only_callsigns: Vec::new(),
+            ignore_callsigns: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct KissConfig {
+    pub enabled: bool,
+    // TCP address on which KISS clients can connect
+    pub address: String,
+}
+
+impl Default for KissConfig {
+    fn default() -> Self {
+        KissConfig {
+            enabled: false,
+            address: "127.0.0.1:8001".to_owned(),
+        }
+    }
+}
+
+// Capture of the frames received and transmitted, in pcapng files for Wireshark
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct CaptureConfig {
+    pub enabled: bool,
+    // The current file. Full files are moved to <path>.1, <path>.2 and so on
+    pub path: String,
+    pub max_file_size_kb: u32,
+    // Files kept, the current one included
+    pub max_files: u32,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            enabled: false,
+            path: "capture.pcapng".to_owned(),
+            max_file_size_kb: 10240,
+            max_files: 5,
+        }
+    }
+}
+
+impl CaptureConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.path.is_empty() {
+            bail!("Capture file path is empty");
+        }
+        if self.max_file_size_kb == 0 {
+            bail!("Maximum capture file size is zero");
+        }
+        // The current file and at least one it is moved to when full
+        if !(2..=100).contains(&self.max_files) {
+            bail!("Number of capture files {} out of range (2 - 100)", self.max_files);
+        }
+        Ok(())
+    }
+}
+
+// Limits on the time spent transmitting, in seconds of airtime per hour
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct AirtimeConfig {
+    pub enabled: bool,
+    pub max_seconds_per_hour: u32,
+    // How long a frame over the limits waits for airtime before being dropped.
+    // Zero drops it right away
+    pub max_delay_seconds: DurationSeconds,
+    // Quotas of the sources of frames, within the overall limit
+    pub ui_seconds_per_hour: Option<u32>,
+    pub beacon_seconds_per_hour: Option<u32>,
+    pub digipeater_seconds_per_hour: Option<u32>,
+    pub felinet_seconds_per_hour: Option<u32>,
+    pub tunnel_seconds_per_hour: Option<u32>,
+    pub kiss_seconds_per_hour: Option<u32>,
+}
+
+impl Default for AirtimeConfig {
+    fn default() -> Self {
+        AirtimeConfig {
+            // Off until enabled, then a 10% duty cycle
+            enabled: false,
+            max_seconds_per_hour: 360,
+            max_delay_seconds: 30,
+            ui_seconds_per_hour: None,
+            beacon_seconds_per_hour: None,
+            digipeater_seconds_per_hour: None,
+            felinet_seconds_per_hour: None,
+            tunnel_seconds_per_hour: None,
+            kiss_seconds_per_hour: None,
+        }
+    }
+}
+
+impl AirtimeConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let limits = [
+            ("Overall", Some(self.max_seconds_per_hour)),
+            ("UI", self.ui_seconds_per_hour),
+            ("Beacon", self.beacon_seconds_per_hour),
+            ("Digipeater", self.digipeater_seconds_per_hour),
+            ("FELINET", self.felinet_seconds_per_hour),
+            ("Tunnel", self.tunnel_seconds_per_hour),
+            ("KISS", self.kiss_seconds_per_hour),
+        ];
+        for (name, limit) in limits {
+            if let Some(limit) = limit.filter(|l| *l > 3600) {
+                bail!("{name} airtime limit of {limit} s per hour is more than an hour");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RadioBackendKind {
+    // RF4463 hat connected over SPI
+    Rf4463,
+    // Fake radio over UDP, for use with the fake-radio tool
+    Udp,
+    // Every transmitted frame is received again, no hardware needed
+    Loopback,
+}
+
+impl std::str::FromStr for RadioBackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rf4463" => Ok(RadioBackendKind::Rf4463),
+            "udp" => Ok(RadioBackendKind::Udp),
+            "loopback" => Ok(RadioBackendKind::Loopback),
+            other => Err(anyhow::anyhow!("Unknown radio backend '{other}'")),
+        }
+    }
+}
+
+// p-persistent CSMA, as used before every transmission
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct CsmaConfig {
+    pub slot_time_ms: u16,
+    // Probability to transmit in a free slot is (persistence + 1) / 256
+    pub persistence: u8,
+    // After waiting that long for the channel, transmit anyway
+    pub max_deferral_ms: u32,
+}
+
+impl Default for CsmaConfig {
+    fn default() -> Self {
+        CsmaConfig {
+            slot_time_ms: 20,
+            persistence: 63,
+            max_deferral_ms: 5000,
+        }
+    }
+}
+
+// The receiver isn't serviced during a slot, longer slots could overflow its FIFO
+const MAX_SLOT_TIME_MS : u16 = 100;
+const MAX_DEFERRAL_MS : u32 = 60_000;
+
+impl CsmaConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=MAX_SLOT_TIME_MS).contains(&self.slot_time_ms) {
+            bail!("Slot time of {} ms out of range (1 - {MAX_SLOT_TIME_MS} ms)", self.slot_time_ms);
+        }
+
+        if self.max_deferral_ms > MAX_DEFERRAL_MS {
+            bail!("Maximum deferral of {} ms is more than {MAX_DEFERRAL_MS} ms", self.max_deferral_ms);
+        }
+
+        Ok(())
+    }
+}
+
+// Wiring of the RF4463 hat. Pins are BCM GPIO numbers
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RadioConfig {
+    pub backend: RadioBackendKind,
+    pub spi_bus: u8,
+    pub spi_slave_select: u8,
+    pub spi_clock_hz: u32,
+    pub sdn_pin: u8,
+    pub cs_pin: u8,
+    pub irq_pin: Option<u8>,
+    pub csma: CsmaConfig,
+    // UDP backend only: percentage of the slots in which the channel is
+    // reported busy, on top of the frames being received
+    pub simulated_busy_percent: u8,
+    // UDP backend only: where frames are received, and where they are sent
+    pub udp_rx_address: String,
+    pub udp_tx_address: String,
+}
+
+impl Default for RadioConfig {
+    fn default() -> Self {
+        RadioConfig {
+            backend: RadioBackendKind::Rf4463,
+            spi_bus: 0,
+            spi_slave_select: 0,
+            spi_clock_hz: 1_000_000,
+            sdn_pin: 22,
+            cs_pin: 24,
+            irq_pin: None,
+            csma: Default::default(),
+            simulated_busy_percent: 0,
+            udp_rx_address: "127.0.0.1:9073".to_owned(),
+            udp_tx_address: "127.0.0.1:9074".to_owned(),
+        }
+    }
+}
+
+// The RF4463 SPI interface runs at up to 10 MHz
+const MAX_SPI_CLOCK_HZ : u32 = 10_000_000;
+const MIN_SPI_CLOCK_HZ : u32 = 100_000;
+// GPIOs available on the 40-pin header
+const MAX_GPIO : u8 = 27;
+
+impl RadioConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.spi_bus > 6 {
+            bail!("SPI bus {} does not exist, expected 0 to 6", self.spi_bus);
+        }
+
+        if self.spi_slave_select > 2 {
+            bail!("SPI slave select {} does not exist, expected 0 to 2", self.spi_slave_select);
+        }
+
+        if !(MIN_SPI_CLOCK_HZ..=MAX_SPI_CLOCK_HZ).contains(&self.spi_clock_hz) {
+            bail!("SPI clock {} Hz out of range ({MIN_SPI_CLOCK_HZ} - {MAX_SPI_CLOCK_HZ} Hz)", self.spi_clock_hz);
+        }
+
+        let pins = [("SDN", Some(self.sdn_pin)), ("CS", Some(self.cs_pin)), ("IRQ", self.irq_pin)];
+        for (i, (name, pin)) in pins.iter().enumerate() {
+            let Some(pin) = pin else { continue };
+
+            if *pin > MAX_GPIO {
+                bail!("{name} pin GPIO{pin} does not exist, expected 0 to {MAX_GPIO}");
+            }
+
+            if let Some((other, _)) = pins[..i].iter().find(|(_, p)| *p == Some(*pin)) {
+                bail!("{name} and {other} pins are both set to GPIO{pin}");
+            }
+        }
+
+        if self.simulated_busy_percent > 100 {
+            bail!("Simulated busy channel of {}% is more than 100%", self.simulated_busy_percent);
+        }
+
+        for (name, address) in [("UDP RX", &self.udp_rx_address), ("UDP TX", &self.udp_tx_address)] {
+            if address.parse::<std::net::SocketAddr>().is_err() {
+                bail!("{name} address '{address}' is not an IP address and port");
+            }
+        }
+
+        self.csma.validate()?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Config {
     pub freq: u32, // kHz
     pub callsign: String,
@@ -77,6 +366,23 @@ pub struct Config {
     pub felinet: FelinetConfig,
     pub beacon: BeaconConfig,
     pub tunnel: TunnelConfig,
+    #[serde(default)]
+    pub digipeater: DigipeaterConfig,
+    #[serde(default)]
+    pub radio: RadioConfig,
+    #[serde(default)]
+    pub kiss: KissConfig,
+    #[serde(default)]
+    pub airtime: AirtimeConfig,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    // Port of the web UI
+    #[serde(default = "default_ui_port")]
+    pub ui_port: u16,
+}
+
+fn default_ui_port() -> u16 {
+    3000
 }
 
 impl Default for Config {
@@ -89,17 +395,54 @@ impl Default for Config {
             felinet: Default::default(),
             beacon: Default::default(),
             tunnel: Default::default(),
+            digipeater: Default::default(),
+            radio: Default::default(),
+            kiss: Default::default(),
+            airtime: Default::default(),
+            capture: Default::default(),
+            ui_port: default_ui_port(),
         }
     }
 }
 
+// Range and raster of the RF4463 channels, in kHz
+pub const MIN_FREQ : u32 = 430000;
+pub const MAX_FREQ : u32 = 436375;
+pub const CHANNEL_SPACING : u32 = 25;
+
+// RF4463 channel number of the given frequency in kHz
+pub fn channel_for_frequency(freq: u32) -> anyhow::Result<u8> {
+    if !(MIN_FREQ..=MAX_FREQ).contains(&freq) {
+        bail!("Frequency {freq} kHz out of range (430MHz - 436.375MHz)");
+    }
+
+    if (freq - MIN_FREQ) % CHANNEL_SPACING != 0 {
+        bail!("Frequency {freq} kHz is not a multiple of {CHANNEL_SPACING} kHz");
+    }
+
+    Ok(((freq - MIN_FREQ) / CHANNEL_SPACING).try_into()?)
+}
+
 const CONFIGFILE : &str = "node-config.toml";
 
 impl Config {
+    // A frequency of zero means there is no radio, and selects the UDP fake radio
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.freq != 0 {
+            channel_for_frequency(self.freq)?;
+        }
+        self.radio.validate().context("invalid [radio] section")?;
+        self.airtime.validate().context("invalid [airtime] section")?;
+        self.capture.validate().context("invalid [capture] section")?;
+        Ok(())
+    }
+
     pub fn load() -> anyhow::Result<Self> {
         if std::path::Path::new(CONFIGFILE).exists() {
             let file_contents = fs::read_to_string(CONFIGFILE)?;
-            toml::from_str(&file_contents).context("parsing config file")
+            let conf : Self = toml::from_str(&file_contents).context("parsing config file")?;
+            conf.validate().context("invalid config file")?;
+            Ok(conf)
         }
         else {
             Ok(Default::default())
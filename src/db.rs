@@ -1,13 +1,194 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use log::debug;
+use serde::Serialize;
 use sqlx::{SqlitePool, sqlite::SqliteRow, Row};
 
 #[derive(Clone)]
 pub struct Database {
     pool : SqlitePool,
-    num_frames_received : u64,
+    // Shared by the clones, which all store frames
+    num_frames_received : Arc<AtomicU64>,
+    num_frames_undecodable : Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Direction {
+    #[serde(rename = "rx")]
+    Received,
+    #[serde(rename = "tx")]
+    Transmitted,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Received => "rx",
+            Direction::Transmitted => "tx",
+        }
+    }
+
+    fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "rx" => Some(Direction::Received),
+            "tx" => Some(Direction::Transmitted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DecodeStatus {
+    Ok,
+    Failed,
+}
+
+impl DecodeStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DecodeStatus::Ok => "ok",
+            DecodeStatus::Failed => "failed",
+        }
+    }
+
+    fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "ok" => Some(DecodeStatus::Ok),
+            "failed" => Some(DecodeStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+// Who asked for a frame to be transmitted
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TxSource {
+    Ui,
+    Beacon,
+    Digipeater,
+    Felinet,
+    Tunnel,
+    Kiss,
+}
+
+impl TxSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxSource::Ui => "ui",
+            TxSource::Beacon => "beacon",
+            TxSource::Digipeater => "digipeater",
+            TxSource::Felinet => "felinet",
+            TxSource::Tunnel => "tunnel",
+            TxSource::Kiss => "kiss",
+        }
+    }
+
+    fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "ui" => Some(TxSource::Ui),
+            "beacon" => Some(TxSource::Beacon),
+            "digipeater" => Some(TxSource::Digipeater),
+            "felinet" => Some(TxSource::Felinet),
+            "tunnel" => Some(TxSource::Tunnel),
+            "kiss" => Some(TxSource::Kiss),
+            _ => None,
+        }
+    }
+}
+
+// Delivery state of a frame handed to the radio
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TxState {
+    Queued,
+    Transmitting,
+    Sent,
+    Failed,
+    TimedOut,
+    // Removed from the transmit queue from the UI
+    Cancelled,
+    // Waited too long in the transmit queue
+    Expired,
+}
+
+impl TxState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxState::Queued => "queued",
+            TxState::Transmitting => "transmitting",
+            TxState::Sent => "sent",
+            TxState::Failed => "failed",
+            TxState::TimedOut => "timed_out",
+            TxState::Cancelled => "cancelled",
+            TxState::Expired => "expired",
+        }
+    }
+
+    fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "queued" => Some(TxState::Queued),
+            "transmitting" => Some(TxState::Transmitting),
+            "sent" => Some(TxState::Sent),
+            "failed" => Some(TxState::Failed),
+            "timed_out" => Some(TxState::TimedOut),
+            "cancelled" => Some(TxState::Cancelled),
+            "expired" => Some(TxState::Expired),
+            _ => None,
+        }
+    }
+}
+
+// Link information stored alongside each frame
+#[derive(Debug, Clone)]
+pub struct FrameMetadata {
+    pub direction : Direction,
+    pub rssi : Option<f64>,
+    pub freq : Option<u32>, // kHz
+    pub decode_status : DecodeStatus,
+    // Reason why decoding failed
+    pub decode_error : Option<String>,
+    // Id of the first copy of this packet, if this frame is a duplicate
+    pub duplicate_of : Option<i64>,
+}
+
+impl FrameMetadata {
+    pub fn received(rssi: f64, freq: Option<u32>) -> Self {
+        Self {
+            direction: Direction::Received,
+            rssi: Some(rssi),
+            freq,
+            decode_status: DecodeStatus::Ok,
+            decode_error: None,
+            duplicate_of: None,
+        }
+    }
+
+    pub fn undecodable(rssi: f64, freq: Option<u32>, decode_error: String) -> Self {
+        Self {
+            direction: Direction::Received,
+            rssi: Some(rssi),
+            freq,
+            decode_status: DecodeStatus::Failed,
+            decode_error: Some(decode_error),
+            duplicate_of: None,
+        }
+    }
+
+    pub fn transmitted(freq: Option<u32>) -> Self {
+        Self {
+            direction: Direction::Transmitted,
+            rssi: None,
+            freq,
+            decode_status: DecodeStatus::Ok,
+            decode_error: None,
+            duplicate_of: None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -15,6 +196,29 @@ pub struct Packet {
     pub id : i64,
     pub received_at: chrono::DateTime<chrono::Utc>,
     pub content : Vec<u8>,
+    pub rssi : Option<f64>,
+    pub direction : Direction,
+    pub freq : Option<u32>, // kHz
+    pub decode_status : DecodeStatus,
+    pub decode_error : Option<String>,
+    pub duplicate_of : Option<i64>,
+}
+
+impl Packet {
+    // A packet just stored with the given id
+    pub fn new(id: i64, content: Vec<u8>, metadata: &FrameMetadata) -> Self {
+        Self {
+            id,
+            received_at: chrono::Utc::now(),
+            content,
+            rssi: metadata.rssi,
+            direction: metadata.direction,
+            freq: metadata.freq,
+            decode_status: metadata.decode_status,
+            decode_error: metadata.decode_error.clone(),
+            duplicate_of: metadata.duplicate_of,
+        }
+    }
 }
 
 impl sqlx::FromRow<'_, SqliteRow> for Packet {
@@ -26,18 +230,86 @@ impl sqlx::FromRow<'_, SqliteRow> for Packet {
                 chrono::DateTime::from_timestamp(row, 0).expect("Convert timestamp to chrono")
             },
             content: row.try_get("content")?,
+            rssi: row.try_get("rssi")?,
+            direction: {
+                let direction : String = row.try_get("direction")?;
+                Direction::from_str(&direction)
+                    .ok_or_else(|| sqlx::Error::Decode(format!("Invalid direction {direction}").into()))?
+            },
+            freq: row.try_get("freq")?,
+            decode_status: {
+                let status : String = row.try_get("decode_status")?;
+                DecodeStatus::from_str(&status)
+                    .ok_or_else(|| sqlx::Error::Decode(format!("Invalid decode status {status}").into()))?
+            },
+            decode_error: row.try_get("decode_error")?,
+            duplicate_of: row.try_get("duplicate_of")?,
+        })
+    }
+}
 
+#[derive(Debug)]
+pub struct Transmission {
+    pub id : i64,
+    pub queued_at : chrono::DateTime<chrono::Utc>,
+    pub updated_at : chrono::DateTime<chrono::Utc>,
+    pub source : TxSource,
+    pub state : TxState,
+    pub error : Option<String>,
+    // Frame without the length prefix
+    pub content : Vec<u8>,
+    // Row in frames_received, once the frame was sent
+    pub frame_id : Option<i64>,
+}
+
+impl sqlx::FromRow<'_, SqliteRow> for Transmission {
+    fn from_row(row: &SqliteRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            queued_at: {
+                let row : i64 = row.try_get("queued_at")?;
+                chrono::DateTime::from_timestamp(row, 0).expect("Convert timestamp to chrono")
+            },
+            updated_at: {
+                let row : i64 = row.try_get("updated_at")?;
+                chrono::DateTime::from_timestamp(row, 0).expect("Convert timestamp to chrono")
+            },
+            source: {
+                let source : String = row.try_get("source")?;
+                TxSource::from_str(&source)
+                    .ok_or_else(|| sqlx::Error::Decode(format!("Invalid transmission source {source}").into()))?
+            },
+            state: {
+                let state : String = row.try_get("state")?;
+                TxState::from_str(&state)
+                    .ok_or_else(|| sqlx::Error::Decode(format!("Invalid transmission state {state}").into()))?
+            },
+            error: row.try_get("error")?,
+            content: row.try_get("content")?,
+            frame_id: row.try_get("frame_id")?,
         })
     }
 }
 
+fn unix_timestamp() -> anyhow::Result<i64> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+
+    Ok(timestamp.as_secs().try_into()?)
+}
+
 impl Database {
     pub async fn new() -> Self {
+        Self::open("cats-radio-node.db").await
+    }
+
+    pub async fn open(path: &str) -> Self {
         {
             // Ensure the database file exists
             match std::fs::OpenOptions::new().write(true)
                 .create_new(true)
-                .open("cats-radio-node.db") {
+                .open(path) {
                     Ok(_f) => (),
                     Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
                     Err(e) => {
@@ -46,7 +318,7 @@ impl Database {
                 }
         }
 
-        let pool = SqlitePool::connect("sqlite:cats-radio-node.db").await.unwrap();
+        let pool = SqlitePool::connect(&format!("sqlite:{path}")).await.unwrap();
         let mut conn = pool.acquire().await.unwrap();
 
         sqlx::migrate!()
@@ -54,41 +326,76 @@ impl Database {
             .await
             .expect("could not run SQLx migrations");
 
-        let num_frames_received : i64 = sqlx::query_scalar(r#"SELECT COUNT(id) FROM frames_received"#)
+        // Frames still in the queue of a previous run will never be transmitted
+        let now = unix_timestamp().unwrap();
+        sqlx::query(r#"
+               UPDATE transmissions SET state = 'failed', error = 'Node restarted', updated_at = ?1
+               WHERE state IN ('queued', 'transmitting')"#)
+            .bind(now)
+            .execute(&pool)
+            .await
+            .expect("could not clean up transmissions");
+
+        let num_frames_received : i64 = sqlx::query_scalar(r#"SELECT COUNT(id) FROM frames_received WHERE direction = 'rx'"#)
             .fetch_one(&pool)
             .await
             .expect("could not count frames");
 
-        Self { pool, num_frames_received: num_frames_received.try_into().unwrap() }
+        let num_frames_undecodable : i64 = sqlx::query_scalar(r#"SELECT COUNT(id) FROM frames_received WHERE decode_status = 'failed'"#)
+            .fetch_one(&pool)
+            .await
+            .expect("could not count undecodable frames");
+
+        Self {
+            pool,
+            num_frames_received: Arc::new(AtomicU64::new(num_frames_received.try_into().unwrap())),
+            num_frames_undecodable: Arc::new(AtomicU64::new(num_frames_undecodable.try_into().unwrap())),
+        }
     }
 
     pub fn get_num_received_frames(&self) -> u64 {
-        self.num_frames_received
+        self.num_frames_received.load(Ordering::Relaxed)
     }
 
-    pub async fn store_packet(&mut self, packet: &[u8]) -> anyhow::Result<()> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards");
+    pub fn get_num_undecodable_frames(&self) -> u64 {
+        self.num_frames_undecodable.load(Ordering::Relaxed)
+    }
 
-        let timestamp_i64 : i64 = timestamp.as_secs().try_into()?;
+    // Returns the id of the new row
+    pub async fn store_packet(&mut self, packet: &[u8], metadata: &FrameMetadata) -> anyhow::Result<i64> {
+        let timestamp_i64 = unix_timestamp()?;
 
-        let id = sqlx::query(r#"INSERT INTO frames_received (received_at, content) VALUES ( ?1 , ?2 )"#)
+        let id = sqlx::query(r#"
+               INSERT INTO frames_received (received_at, content, rssi, direction, freq, decode_status, decode_error, duplicate_of)
+               VALUES ( ?1 , ?2 , ?3 , ?4 , ?5 , ?6 , ?7 , ?8 )"#)
             .bind(timestamp_i64).bind(packet)
+            .bind(metadata.rssi)
+            .bind(metadata.direction.as_str())
+            .bind(metadata.freq)
+            .bind(metadata.decode_status.as_str())
+            .bind(metadata.decode_error.as_deref())
+            .bind(metadata.duplicate_of)
             .execute(&self.pool)
             .await?
             .last_insert_rowid();
 
-        self.num_frames_received += 1;
+        if metadata.direction == Direction::Received {
+            self.num_frames_received.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if metadata.decode_status == DecodeStatus::Failed {
+            self.num_frames_undecodable.fetch_add(1, Ordering::Relaxed);
+        }
 
         debug!("INSERTed row {id}");
-        Ok(())
+        Ok(id)
     }
 
     pub async fn get_most_recent_packets(&mut self, count: i64) -> anyhow::Result<Vec<Packet>> {
         let results = sqlx::query_as(r#"
-               SELECT id, received_at, content
+               SELECT id, received_at, content, rssi, direction, freq, decode_status, decode_error, duplicate_of
                FROM frames_received
+               WHERE duplicate_of IS NULL AND decode_status = 'ok'
                ORDER BY received_at DESC
                LIMIT ?1"#)
             .bind(count)
@@ -100,9 +407,9 @@ impl Database {
 
     pub async fn get_packets_since(&mut self, unix_timestamp: i64) -> anyhow::Result<Vec<Packet>> {
         let results = sqlx::query_as(r#"
-               SELECT id, received_at, content
+               SELECT id, received_at, content, rssi, direction, freq, decode_status, decode_error, duplicate_of
                FROM frames_received
-               WHERE received_at > ?1
+               WHERE received_at > ?1 AND decode_status = 'ok'
                ORDER BY received_at"#)
             .bind(unix_timestamp)
             .fetch_all(&self.pool)
@@ -110,4 +417,120 @@ impl Database {
 
         Ok(results)
     }
+
+    pub async fn get_packet(&mut self, id: i64) -> anyhow::Result<Option<Packet>> {
+        let result = sqlx::query_as(r#"
+               SELECT id, received_at, content, rssi, direction, freq, decode_status, decode_error, duplicate_of
+               FROM frames_received
+               WHERE id = ?1"#)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(result)
+    }
+
+    // All frames stored between the two timestamps, included, duplicates and undecodable frames too
+    pub async fn get_packets_between(&mut self, from: i64, to: i64) -> anyhow::Result<Vec<Packet>> {
+        let results = sqlx::query_as(r#"
+               SELECT id, received_at, content, rssi, direction, freq, decode_status, decode_error, duplicate_of
+               FROM frames_received
+               WHERE received_at >= ?1 AND received_at <= ?2
+               ORDER BY received_at, id"#)
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(results)
+    }
+
+    // Duplicates of the frames with an id of at least first_id
+    pub async fn get_duplicates_since_id(&mut self, first_id: i64) -> anyhow::Result<Vec<Packet>> {
+        let results = sqlx::query_as(r#"
+               SELECT id, received_at, content, rssi, direction, freq, decode_status, decode_error, duplicate_of
+               FROM frames_received
+               WHERE duplicate_of >= ?1
+               ORDER BY received_at"#)
+            .bind(first_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(results)
+    }
+
+    pub async fn get_undecodable_packets(&mut self, count: i64) -> anyhow::Result<Vec<Packet>> {
+        let results = sqlx::query_as(r#"
+               SELECT id, received_at, content, rssi, direction, freq, decode_status, decode_error, duplicate_of
+               FROM frames_received
+               WHERE decode_status = 'failed'
+               ORDER BY received_at DESC
+               LIMIT ?1"#)
+            .bind(count)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(results)
+    }
+
+    // Returns the id of the new transmission
+    pub async fn queue_transmission(&mut self, content: &[u8], source: TxSource) -> anyhow::Result<i64> {
+        let now = unix_timestamp()?;
+
+        let id = sqlx::query(r#"
+               INSERT INTO transmissions (queued_at, updated_at, source, state, content)
+               VALUES ( ?1 , ?1 , ?2 , ?3 , ?4 )"#)
+            .bind(now)
+            .bind(source.as_str())
+            .bind(TxState::Queued.as_str())
+            .bind(content)
+            .execute(&self.pool)
+            .await?
+            .last_insert_rowid();
+
+        Ok(id)
+    }
+
+    pub async fn update_transmission(&mut self, id: i64, state: TxState, error: Option<&str>, frame_id: Option<i64>) -> anyhow::Result<()> {
+        let now = unix_timestamp()?;
+
+        sqlx::query(r#"
+               UPDATE transmissions
+               SET state = ?2, error = ?3, frame_id = COALESCE(?4, frame_id), updated_at = ?5
+               WHERE id = ?1"#)
+            .bind(id)
+            .bind(state.as_str())
+            .bind(error)
+            .bind(frame_id)
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_transmission(&mut self, id: i64) -> anyhow::Result<Option<Transmission>> {
+        let result = sqlx::query_as(r#"
+               SELECT id, queued_at, updated_at, source, state, error, content, frame_id
+               FROM transmissions
+               WHERE id = ?1"#)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn get_most_recent_transmissions(&mut self, count: i64) -> anyhow::Result<Vec<Transmission>> {
+        let results = sqlx::query_as(r#"
+               SELECT id, queued_at, updated_at, source, state, error, content, frame_id
+               FROM transmissions
+               ORDER BY id DESC
+               LIMIT ?1"#)
+            .bind(count)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(results)
+    }
 }
@@ -0,0 +1,122 @@
+use std::collections::{hash_map::DefaultHasher, HashMap};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use ham_cats::packet::Packet;
+
+use crate::radio::MAX_PACKET_LEN;
+
+// Copies of a packet heard within this time after the first one are duplicates
+const DUPLICATE_WINDOW : Duration = Duration::from_secs(300);
+
+/* Identifies a packet independently of the path it took, i.e. by its
+ * decoded content without the Route whisker. Returns None if the frame
+ * cannot be decoded. */
+pub fn content_key(frame: &[u8]) -> Option<u64> {
+    let mut buf = [0; MAX_PACKET_LEN];
+    let mut packet = Packet::fully_decode(frame, &mut buf).ok()?;
+    packet.clear_route();
+
+    let mut hasher = DefaultHasher::new();
+    packet.encode().hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+// Remembers which packets were recently received, and the database id of the first copy
+pub struct DuplicateDetector {
+    recent : HashMap<u64, (Instant, i64)>,
+}
+
+impl DuplicateDetector {
+    pub fn new() -> Self {
+        Self {
+            recent: HashMap::new(),
+        }
+    }
+
+    // Returns the id of the first copy if a packet with this key was received recently
+    pub fn original_of(&mut self, key: u64) -> Option<i64> {
+        self.recent.retain(|_, (heard_at, _)| heard_at.elapsed() < DUPLICATE_WINDOW);
+        self.recent.get(&key).map(|(_, id)| *id)
+    }
+
+    pub fn insert(&mut self, key: u64, id: i64) {
+        self.recent.insert(key, (Instant::now(), id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ham_cats::{
+        identity::Identity,
+        whisker::{Identification, PastHop, Route},
+    };
+
+    use super::*;
+    use crate::framing;
+
+    // A frame from N0CALL with the given comment, repeated by the digipeaters in path
+    fn frame(comment: &str, path: &[(&str, u8)]) -> Vec<u8> {
+        let mut buf = [0; MAX_PACKET_LEN];
+        let mut pkt = Packet::new(&mut buf);
+        pkt.add_identification(Identification::new("N0CALL", 1, 0).unwrap()).unwrap();
+        pkt.add_comment(comment).unwrap();
+
+        let mut route = Route::new(3);
+        for (callsign, ssid) in path {
+            route.push_past(PastHop::new(Identity::new(callsign, *ssid), Some(-90.0))).unwrap();
+        }
+        pkt.add_route(route).unwrap();
+
+        let data = framing::encode_packet(pkt).unwrap();
+        framing::strip_length_prefix(&data).unwrap().to_vec()
+    }
+
+    fn ago(seconds: u64) -> Instant {
+        Instant::now().checked_sub(Duration::from_secs(seconds)).unwrap()
+    }
+
+    #[test]
+    fn same_packet_via_two_paths_is_a_duplicate() {
+        let direct = content_key(&frame("hello", &[])).unwrap();
+        let via_digi = content_key(&frame("hello", &[("DIGI", 1), ("DIGI", 2)])).unwrap();
+        assert_eq!(direct, via_digi);
+
+        let mut duplicates = DuplicateDetector::new();
+        assert_eq!(duplicates.original_of(direct), None);
+        duplicates.insert(direct, 1);
+        assert_eq!(duplicates.original_of(via_digi), Some(1));
+    }
+
+    #[test]
+    fn distinct_packets_are_not_merged() {
+        let first = content_key(&frame("hello", &[])).unwrap();
+        let second = content_key(&frame("hello again", &[])).unwrap();
+        assert_ne!(first, second);
+
+        let mut duplicates = DuplicateDetector::new();
+        duplicates.insert(first, 1);
+        assert_eq!(duplicates.original_of(second), None);
+        duplicates.insert(second, 2);
+        assert_eq!(duplicates.original_of(first), Some(1));
+        assert_eq!(duplicates.original_of(second), Some(2));
+    }
+
+    #[test]
+    fn packet_is_new_after_the_window() {
+        let key = content_key(&frame("hello", &[])).unwrap();
+
+        let mut duplicates = DuplicateDetector::new();
+        duplicates.recent.insert(key, (ago(DUPLICATE_WINDOW.as_secs() - 10), 1));
+        assert_eq!(duplicates.original_of(key), Some(1));
+
+        duplicates.recent.insert(key, (ago(DUPLICATE_WINDOW.as_secs() + 1), 1));
+        assert_eq!(duplicates.original_of(key), None);
+        assert!(duplicates.recent.is_empty());
+    }
+
+    #[test]
+    fn undecodable_frame_has_no_key() {
+        assert_eq!(content_key(&[0xFF; 3]), None);
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::anyhow;
+use log::debug;
+
+use ham_cats::{
+    identity::Identity,
+    packet::Packet,
+};
+
+use crate::{config, framing, radio::MAX_PACKET_LEN};
+
+fn callsign_matches(filter: &str, callsign: &str, ssid: u8) -> bool {
+    match filter.split_once('-') {
+        Some((filter_call, filter_ssid)) => {
+            filter_call.eq_ignore_ascii_case(callsign) && filter_ssid.parse() == Ok(ssid)
+        },
+        None => filter.eq_ignore_ascii_case(callsign),
+    }
+}
+
+/* Returns the frame to transmit if the received frame has to be repeated,
+ * with our callsign appended to its route. Duplicates must be filtered out
+ * before, using the dedup::DuplicateDetector. */
+pub fn digipeat(conf: &config::Config, frame: &[u8], rssi: f64) -> anyhow::Result<Option<Vec<u8>>> {
+    let digi_conf = &conf.digipeater;
+    if !digi_conf.enabled {
+        return Ok(None);
+    }
+
+    let mut buf = [0; MAX_PACKET_LEN];
+    let mut packet = Packet::fully_decode(frame, &mut buf)
+        .map_err(|e| anyhow!("Could not decode packet: {e}"))?;
+
+    let Some(ident) = packet.identification() else {
+        debug!("Digipeater: ignoring packet without identification");
+        return Ok(None);
+    };
+
+    if digi_conf.ignore_callsigns.iter().any(|f| callsign_matches(f, &ident.callsign, ident.ssid)) {
+        debug!("Digipeater: ignoring {}-{}", ident.callsign, ident.ssid);
+        return Ok(None);
+    }
+
+    if !digi_conf.only_callsigns.is_empty() &&
+        !digi_conf.only_callsigns.iter().any(|f| callsign_matches(f, &ident.callsign, ident.ssid)) {
+        debug!("Digipeater: {}-{} not in the list of repeated callsigns", ident.callsign, ident.ssid);
+        return Ok(None);
+    }
+
+    match packet.route() {
+        Some(route) if route.max_hops > digi_conf.max_hops => {
+            debug!("Digipeater: route asks for {} hops, accepting at most {}", route.max_hops, digi_conf.max_hops);
+            return Ok(None);
+        },
+        Some(_) => (),
+        None => {
+            debug!("Digipeater: packet has no route");
+            return Ok(None);
+        },
+    }
+
+    if let Err(e) = packet.should_digipeat(Identity::new(&conf.callsign, conf.ssid)) {
+        debug!("Digipeater: not repeating: {e}");
+        return Ok(None);
+    }
+
+    packet.append_to_route(&conf.callsign, conf.ssid, Some(rssi))
+        .map_err(|e| anyhow!("Could not append to route: {e}"))?;
+
+    framing::encode_packet(packet).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use ham_cats::whisker::{Identification, PastHop, Route, RouteHop};
+
+    use super::*;
+
+    // A frame from the given station, with a route if max_hops is set
+    fn frame(from: (&str, u8), max_hops: Option<u8>, path: &[(&str, u8)]) -> Vec<u8> {
+        let mut buf = [0; MAX_PACKET_LEN];
+        let mut pkt = Packet::new(&mut buf);
+        pkt.add_identification(Identification::new(from.0, from.1, 0).unwrap()).unwrap();
+        pkt.add_comment("hello").unwrap();
+
+        if let Some(max_hops) = max_hops {
+            let mut route = Route::new(max_hops);
+            for (callsign, ssid) in path {
+                route.push_past(PastHop::new(Identity::new(callsign, *ssid), None)).unwrap();
+            }
+            pkt.add_route(route).unwrap();
+        }
+
+        let data = framing::encode_packet(pkt).unwrap();
+        framing::strip_length_prefix(&data).unwrap().to_vec()
+    }
+
+    fn digi_config(only_callsigns: &[&str], ignore_callsigns: &[&str]) -> config::Config {
+        config::Config {
+            callsign: "DIGI".to_owned(),
+            ssid: 1,
+            digipeater: config::DigipeaterConfig {
+                enabled: true,
+                max_hops: 3,
+                only_callsigns: only_callsigns.iter().map(|c| c.to_string()).collect(),
+                ignore_callsigns: ignore_callsigns.iter().map(|c| c.to_string()).collect(),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn repeats_according_to_config_and_route() {
+        struct Case {
+            name : &'static str,
+            from : (&'static str, u8),
+            max_hops : Option<u8>,
+            path : &'static [(&'static str, u8)],
+            only : &'static [&'static str],
+            ignore : &'static [&'static str],
+            repeated : bool,
+        }
+
+        let base = Case { name: "", from: ("N0CALL", 1), max_hops: Some(3), path: &[], only: &[], ignore: &[], repeated: true };
+        let cases = [
+            Case { name: "no filter", ..base },
+            Case { name: "ignored callsign", ignore: &["N0CALL"], repeated: false, ..base },
+            Case { name: "ignored callsign, any case", ignore: &["n0call-1"], repeated: false, ..base },
+            Case { name: "other SSID ignored", ignore: &["N0CALL-2"], ..base },
+            Case { name: "only callsign", only: &["N0CALL"], ..base },
+            Case { name: "only callsign and SSID", only: &["OTHER", "N0CALL-1"], ..base },
+            Case { name: "only other SSID", only: &["N0CALL-2"], repeated: false, ..base },
+            Case { name: "only other callsign", only: &["OTHER"], repeated: false, ..base },
+            Case { name: "ignore wins over only", only: &["N0CALL"], ignore: &["N0CALL-1"], repeated: false, ..base },
+            Case { name: "no route", max_hops: None, repeated: false, ..base },
+            Case { name: "more hops than allowed", max_hops: Some(4), repeated: false, ..base },
+            Case { name: "hops left", path: &[("A", 0), ("B", 0)], ..base },
+            Case { name: "hops used up", path: &[("A", 0), ("B", 0), ("C", 0)], repeated: false, ..base },
+            Case { name: "already in route", path: &[("DIGI", 1)], repeated: false, ..base },
+            Case { name: "other SSID in route", path: &[("DIGI", 2)], ..base },
+            Case { name: "own callsign", from: ("DIGI", 1), repeated: false, ..base },
+        ];
+
+        for case in cases {
+            let conf = digi_config(case.only, case.ignore);
+            let result = digipeat(&conf, &frame(case.from, case.max_hops, case.path), -90.0).unwrap();
+            assert_eq!(result.is_some(), case.repeated, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn appends_own_callsign_to_route() {
+        let conf = digi_config(&[], &[]);
+        let repeated = digipeat(&conf, &frame(("N0CALL", 1), Some(3), &[("A", 0)]), -90.0).unwrap().unwrap();
+
+        let mut buf = [0; MAX_PACKET_LEN];
+        let packet = Packet::fully_decode(framing::strip_length_prefix(&repeated).unwrap(), &mut buf).unwrap();
+        let route = packet.route().unwrap();
+        let hops : Vec<_> = route.iter()
+            .map(|hop| match hop {
+                RouteHop::Past(hop) => (hop.identity().callsign().to_owned(), hop.identity().ssid()),
+                _ => panic!("unexpected hop {hop:?}"),
+            })
+            .collect();
+        assert_eq!(hops, [("A".to_owned(), 0), ("DIGI".to_owned(), 1)]);
+    }
+
+    #[test]
+    fn disabled_digipeater_repeats_nothing() {
+        let mut conf = digi_config(&[], &[]);
+        conf.digipeater.enabled = false;
+        assert_eq!(digipeat(&conf, &frame(("N0CALL", 1), Some(3), &[]), -90.0).unwrap(), None);
+    }
+}
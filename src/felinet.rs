@@ -0,0 +1,261 @@
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context};
+use log::{debug, info, warn};
+use serde::Serialize;
+use tokio::sync::{mpsc, watch};
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+
+use ham_cats::{buffer::Buffer, packet::Packet, whisker::{Route, RouteHop}};
+
+use crate::felinet_proto::{handler_client::HandlerClient, PacketIn, PacketOut, PacketRequest};
+use crate::{config, db, framing, radio::MAX_PACKET_LEN, transmit, SharedState};
+
+const MIN_BACKOFF : Duration = Duration::from_secs(1);
+const MAX_BACKOFF : Duration = Duration::from_secs(300);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Disabled,
+    Connecting,
+    Connected,
+    Backoff,
+}
+
+// Igate status, shown on the dashboard
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub state : State,
+    pub num_uplinked : u64,
+    pub num_downlinked : u64,
+    pub last_error : Option<String>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status {
+            state: State::Disabled,
+            num_uplinked: 0,
+            num_downlinked: 0,
+            last_error: None,
+        }
+    }
+}
+
+impl Status {
+    pub fn state_str(&self) -> &'static str {
+        match self.state {
+            State::Disabled => "disabled",
+            State::Connecting => "connecting",
+            State::Connected => "connected",
+            State::Backoff => "waiting to reconnect",
+        }
+    }
+}
+
+fn update_status<F: FnOnce(&mut Status)>(shared_state: &SharedState, f: F) {
+    f(&mut shared_state.lock().unwrap().felinet);
+}
+
+// Convert an on-air frame to the semi-encoded form FELINET uses,
+// unless it already went through the Internet.
+fn frame_to_felinet(frame: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut buf = [0; MAX_PACKET_LEN];
+    let packet = Packet::fully_decode(frame, &mut buf)
+        .map_err(|e| anyhow!("Could not decode packet: {e}"))?;
+
+    // Retransmitted by an igate, the server already has it
+    if packet.route().is_some_and(|route| route.iter().any(|hop| hop == RouteHop::Internet)) {
+        return Ok(None);
+    }
+
+    let semi_encoded = packet.semi_encode()
+        .map_err(|(e, _)| anyhow!("Could not encode packet: {e}"))?;
+    Ok(Some(semi_encoded.to_vec()))
+}
+
+// Convert a semi-encoded packet received from FELINET to a frame ready for transmission,
+// unless it originates from this node.
+fn felinet_to_frame(raw: &[u8], config: &config::Config) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut buf = [0; MAX_PACKET_LEN];
+    let mut data = Buffer::new_empty(&mut buf);
+    data.try_extend_from_slice(raw)
+        .map_err(|_| anyhow!("Packet of {} bytes too long", raw.len()))?;
+    let mut packet = Packet::semi_decode(data)
+        .map_err(|e| anyhow!("Could not decode packet: {e}"))?;
+
+    if let Some(ident) = packet.identification() {
+        if ident.callsign.as_str() == config.callsign && ident.ssid == config.ssid {
+            return Ok(None);
+        }
+    }
+
+    // Mark the packet as coming from the Internet, so that no igate sends it back.
+    // Without a route of its own, it is not digipeated.
+    let mut route = packet.route().unwrap_or(Route::new(0));
+    route.push_internet()
+        .context("Could not add the Internet hop to the route")?;
+    packet.clear_route();
+    packet.add_route(route)
+        .map_err(|e| anyhow!("Could not add route to packet: {e}"))?;
+
+    framing::encode_packet(packet).map(Some)
+}
+
+async fn connect(address: &str) -> anyhow::Result<HandlerClient<Channel>> {
+    let mut endpoint = Endpoint::from_shared(address.to_owned())
+        .with_context(|| format!("Invalid FELINET address {address}"))?;
+
+    if address.starts_with("https://") {
+        endpoint = endpoint.tls_config(ClientTlsConfig::new())?;
+    }
+
+    let channel = endpoint.connect().await
+        .with_context(|| format!("Could not connect to {address}"))?;
+    Ok(HandlerClient::new(channel))
+}
+
+async fn next_downlink(downlink: &mut Option<tonic::Streaming<PacketOut>>) -> Result<Option<PacketOut>, tonic::Status> {
+    match downlink {
+        Some(stream) => stream.message().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn retransmit(shared_state: &SharedState, raw: &[u8]) -> anyhow::Result<()> {
+    let conf = shared_state.lock().unwrap().config();
+
+    if let Some(frame) = felinet_to_frame(raw, &conf)? {
+        transmit::queue(shared_state, frame, db::TxSource::Felinet).await?;
+    }
+
+    Ok(())
+}
+
+// Stays connected until an error occurs or the FELINET configuration changes
+async fn session(
+    shared_state: &SharedState,
+    conf: &config::FelinetConfig,
+    conf_receive: &mut watch::Receiver<config::Config>,
+    uplink: &mut mpsc::Receiver<Vec<u8>>,
+    backoff: &mut Duration) -> anyhow::Result<()> {
+    let mut client = connect(&conf.address).await?;
+
+    let mut downlink = if conf.downlink {
+        Some(client.get_packets(PacketRequest {}).await?.into_inner())
+    }
+    else {
+        None
+    };
+
+    info!("FELINET connected to {}", conf.address);
+    update_status(shared_state, |s| s.state = State::Connected);
+    *backoff = MIN_BACKOFF;
+
+    // Frames received while connecting are stale by now
+    discard_frames(uplink);
+
+    loop {
+        tokio::select! {
+            frame = uplink.recv() => {
+                let frame = frame.context("Uplink queue closed")?;
+                match frame_to_felinet(&frame) {
+                    Ok(Some(raw)) => {
+                        client.post_packet(PacketIn { raw }).await?;
+                        update_status(shared_state, |s| s.num_uplinked += 1);
+                    },
+                    Ok(None) => debug!("FELINET: not forwarding frame that came from the Internet"),
+                    Err(e) => warn!("FELINET: not forwarding frame: {e}"),
+                }
+            },
+            packet = next_downlink(&mut downlink) => {
+                match packet? {
+                    Some(packet) => {
+                        debug!("FELINET: received {} bytes", packet.raw.len());
+                        update_status(shared_state, |s| s.num_downlinked += 1);
+                        if let Err(e) = retransmit(shared_state, &packet.raw).await {
+                            warn!("FELINET: not retransmitting packet: {e}");
+                        }
+                    },
+                    None => bail!("Server closed the packet stream"),
+                }
+            },
+            changed = conf_receive.changed() => {
+                changed.context("Configuration channel closed")?;
+                if conf_receive.borrow().felinet != *conf {
+                    info!("FELINET configuration changed, reconnecting");
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
+
+fn discard_frames(uplink: &mut mpsc::Receiver<Vec<u8>>) {
+    let mut num_discarded = 0;
+    while uplink.try_recv().is_ok() {
+        num_discarded += 1;
+    }
+    if num_discarded > 0 {
+        debug!("FELINET: discarded {num_discarded} frames received while disconnected");
+    }
+}
+
+/* Forward every frame received on the uplink queue to the FELINET server,
+ * and optionally transmit the packets the server sends us. */
+pub async fn run(shared_state: SharedState, mut uplink: mpsc::Receiver<Vec<u8>>) {
+    let mut backoff = MIN_BACKOFF;
+    let mut conf_receive = shared_state.lock().unwrap().conf.subscribe();
+
+    loop {
+        let conf = conf_receive.borrow_and_update().felinet.clone();
+
+        if !conf.enabled {
+            update_status(&shared_state, |s| s.state = State::Disabled);
+            // Discard frames while disabled
+            tokio::select! {
+                frame = uplink.recv() => if frame.is_none() {
+                    warn!("FELINET uplink queue closed, stopping");
+                    return;
+                },
+                changed = conf_receive.changed() => if changed.is_err() {
+                    return;
+                },
+            }
+            continue;
+        }
+
+        update_status(&shared_state, |s| s.state = State::Connecting);
+
+        if let Err(e) = session(&shared_state, &conf, &mut conf_receive, &mut uplink, &mut backoff).await {
+            warn!("FELINET error: {e:#}, retrying in {}s", backoff.as_secs());
+            update_status(&shared_state, |s| {
+                s.state = State::Backoff;
+                s.last_error = Some(format!("{e:#}"));
+            });
+
+            // A configuration change, e.g. a new address, is tried right away.
+            // Frames received meanwhile are discarded, they would be stale once connected.
+            let sleep = tokio::time::sleep(backoff);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => {
+                        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+                        break;
+                    },
+                    frame = uplink.recv() => if frame.is_none() {
+                        warn!("FELINET uplink queue closed, stopping");
+                        return;
+                    },
+                    changed = conf_receive.changed() => if changed.is_err() {
+                        return;
+                    } else {
+                        backoff = MIN_BACKOFF;
+                        break;
+                    },
+                }
+            }
+        }
+    }
+}
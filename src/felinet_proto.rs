@@ -0,0 +1,25 @@
+// Messages and service definition of the FELINET gRPC protocol.
+// The client and server stubs are generated by build.rs from a manual
+// service description, so that building does not require protoc.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PacketIn {
+    // Semi-encoded CATS packet: whiskers and CRC, without FEC
+    #[prost(bytes = "vec", tag = "1")]
+    pub raw: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PacketOut {
+    // Semi-encoded CATS packet: whiskers and CRC, without FEC
+    #[prost(bytes = "vec", tag = "1")]
+    pub raw: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PacketRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Empty {}
+
+include!(concat!(env!("OUT_DIR"), "/felinet.Handler.rs"));
@@ -0,0 +1,143 @@
+// Framing of CATS frames between the node, the radio backends and the tools.
+// Also used by the fake-radio and kiss-client tools.
+
+/* A frame is a CATS packet as it is sent on the air, with FEC and interleaving
+ * applied. The radio returns received frames as such, and frames are stored
+ * in the database, exchanged with KISS clients and shown in the UI that way.
+ *
+ * The radio transmits a frame after a 2-byte little endian length prefix,
+ * which Packet::fully_encode adds. The transmit queue holds prefixed frames.
+ *
+ * Datagrams of the UDP backend carry a prefixed frame, optionally followed by
+ * the RSSI in dBm as a little endian f32. */
+use anyhow::{anyhow, bail, Context};
+use ham_cats::{buffer::Buffer, packet::Packet};
+
+pub const PREFIX_LEN : usize = 2;
+const RSSI_LEN : usize = 4;
+
+const MAX_FRAME_LEN : usize = 8191;
+// Largest datagram of the UDP backend, a prefixed frame of maximum length with the RSSI
+pub const MAX_DATAGRAM_LEN : usize = PREFIX_LEN + MAX_FRAME_LEN + RSSI_LEN;
+
+// Encode a packet for transmission, length prefix included
+pub fn encode_packet<const N: usize>(packet: Packet<N>) -> anyhow::Result<Vec<u8>> {
+    let mut buf = [0; MAX_FRAME_LEN];
+    let mut data = Buffer::new_empty(&mut buf);
+    packet.fully_encode(&mut data)
+        .map_err(|e| anyhow!("Could not encode packet: {e}"))?;
+    Ok(data.to_vec())
+}
+
+pub fn add_length_prefix(frame: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let len : u16 = frame.len().try_into().context("Frame too long")?;
+    let mut data = len.to_le_bytes().to_vec();
+    data.extend_from_slice(frame);
+    Ok(data)
+}
+
+// Split the frame from the data following it
+fn split_frame(data: &[u8]) -> anyhow::Result<(&[u8], &[u8])> {
+    if data.len() < PREFIX_LEN {
+        bail!("Frame too short");
+    }
+
+    let len = u16::from_le_bytes([data[0], data[1]]) as usize;
+    if data.len() < PREFIX_LEN + len {
+        bail!("Frame shorter than its length prefix");
+    }
+
+    Ok(data[PREFIX_LEN..].split_at(len))
+}
+
+// The frame without its length prefix
+pub fn strip_length_prefix(data: &[u8]) -> anyhow::Result<&[u8]> {
+    match split_frame(data)? {
+        (frame, []) => Ok(frame),
+        _ => bail!("Frame longer than its length prefix"),
+    }
+}
+
+pub fn encode_datagram(data: &[u8], rssi: Option<f64>) -> Vec<u8> {
+    let mut datagram = data.to_vec();
+    if let Some(rssi) = rssi {
+        datagram.extend_from_slice(&(rssi as f32).to_le_bytes());
+    }
+    datagram
+}
+
+// The frame without its length prefix, and the RSSI if the datagram has one
+pub fn decode_datagram(datagram: &[u8]) -> anyhow::Result<(&[u8], Option<f64>)> {
+    match split_frame(datagram)? {
+        (frame, []) => Ok((frame, None)),
+        (frame, &[a, b, c, d]) => Ok((frame, Some(f32::from_le_bytes([a, b, c, d]).into()))),
+        (_, trailer) => bail!("Unexpected {} bytes after the frame, expected {RSSI_LEN}", trailer.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_prefix_round_trip() {
+        let data = add_length_prefix(&[1, 2, 3]).unwrap();
+        assert_eq!(data, [3, 0, 1, 2, 3]);
+        assert_eq!(strip_length_prefix(&data).unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn max_length_frame() {
+        let frame = vec![0x5A; MAX_FRAME_LEN];
+        let data = add_length_prefix(&frame).unwrap();
+        assert_eq!(data.len(), PREFIX_LEN + MAX_FRAME_LEN);
+        assert_eq!(strip_length_prefix(&data).unwrap(), frame);
+
+        let datagram = encode_datagram(&data, Some(-120.0));
+        assert_eq!(datagram.len(), MAX_DATAGRAM_LEN);
+        assert_eq!(decode_datagram(&datagram).unwrap(), (&frame[..], Some(-120.0)));
+    }
+
+    #[test]
+    fn frame_too_long_for_prefix() {
+        assert!(add_length_prefix(&vec![0; 0x10000]).is_err());
+    }
+
+    #[test]
+    fn prefix_too_short() {
+        assert!(strip_length_prefix(&[]).is_err());
+        assert!(strip_length_prefix(&[3]).is_err());
+        assert!(decode_datagram(&[3]).is_err());
+    }
+
+    #[test]
+    fn frame_shorter_than_prefix() {
+        assert!(strip_length_prefix(&[3, 0, 1, 2]).is_err());
+        assert!(decode_datagram(&[3, 0, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn frame_longer_than_prefix() {
+        assert!(strip_length_prefix(&[2, 0, 1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn datagram_without_rssi() {
+        let datagram = encode_datagram(&[2, 0, 1, 2], None);
+        assert_eq!(datagram, [2, 0, 1, 2]);
+        assert_eq!(decode_datagram(&datagram).unwrap(), (&[1, 2][..], None));
+    }
+
+    #[test]
+    fn datagram_with_rssi() {
+        let datagram = encode_datagram(&[2, 0, 1, 2], Some(-80.5));
+        assert_eq!(datagram[4..], (-80.5f32).to_le_bytes());
+        assert_eq!(decode_datagram(&datagram).unwrap(), (&[1, 2][..], Some(-80.5)));
+    }
+
+    #[test]
+    fn datagram_with_bad_trailer() {
+        assert!(decode_datagram(&[2, 0, 1, 2, 0xFF]).is_err());
+        assert!(decode_datagram(&[2, 0, 1, 2, 0, 0, 0, 0, 0]).is_err());
+    }
+}
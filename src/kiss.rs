@@ -0,0 +1,194 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+
+use crate::kiss_codec::{self, CMD_DATA};
+use crate::{config, db, framing, radio::MAX_PACKET_LEN, transmit, SharedState};
+
+const RETRY_INTERVAL : Duration = Duration::from_secs(10);
+
+// Frames exchanged with KISS clients are CATS frames without the length prefix
+fn frame_to_transmit(frame: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut buf = [0; MAX_PACKET_LEN];
+    ham_cats::packet::Packet::fully_decode(frame, &mut buf)
+        .map_err(|e| anyhow!("Invalid CATS frame: {e}"))?;
+
+    framing::add_length_prefix(frame)
+}
+
+#[derive(Debug, PartialEq)]
+enum KissFrame<'a> {
+    Data(&'a [u8]),
+    // Data for another port than 0, where the node has its single radio
+    OtherPort(u8),
+    // TXDELAY, persistence and similar settings, handled by the radio
+    Command(u8),
+}
+
+fn parse_kiss_frame(kiss_frame: &[u8]) -> Option<KissFrame<'_>> {
+    let (&type_byte, frame) = kiss_frame.split_first()?;
+
+    if type_byte & 0x0F != CMD_DATA {
+        Some(KissFrame::Command(type_byte))
+    }
+    else if type_byte >> 4 != 0 {
+        Some(KissFrame::OtherPort(type_byte >> 4))
+    }
+    else {
+        Some(KissFrame::Data(frame))
+    }
+}
+
+async fn handle_kiss_frame(shared_state: &SharedState, addr: SocketAddr, kiss_frame: &[u8]) {
+    let frame = match parse_kiss_frame(kiss_frame) {
+        Some(KissFrame::Data(frame)) => frame,
+        Some(KissFrame::OtherPort(port)) => {
+            debug!("KISS client {addr}: dropping data frame for port {port}");
+            return;
+        },
+        Some(KissFrame::Command(type_byte)) => {
+            debug!("KISS client {addr}: ignoring command {type_byte:#04x}");
+            return;
+        },
+        None => return,
+    };
+
+    match frame_to_transmit(frame) {
+        Ok(data) => if let Err(e) = transmit::queue(shared_state, data, db::TxSource::Kiss).await {
+            warn!("KISS client {addr}: failed to queue frame: {e}");
+        },
+        Err(e) => warn!("KISS client {addr}: {e}"),
+    }
+}
+
+async fn handle_client(shared_state: SharedState, stream: TcpStream, addr: SocketAddr, conf: config::KissConfig) {
+    let (mut received, mut conf_receive) = {
+        let s = shared_state.lock().unwrap();
+        (s.kiss_broadcast.subscribe(), s.conf.subscribe())
+    };
+    let (mut reader, mut writer) = stream.into_split();
+    let mut decoder = kiss_codec::Decoder::new(MAX_PACKET_LEN + 1);
+    let mut buf = [0; 1024];
+
+    loop {
+        tokio::select! {
+            read = reader.read(&mut buf) => {
+                match read {
+                    Ok(0) => break,
+                    Ok(len) => {
+                        for kiss_frame in decoder.push(&buf[..len]) {
+                            handle_kiss_frame(&shared_state, addr, &kiss_frame).await;
+                        }
+                    },
+                    Err(e) => {
+                        warn!("KISS client {addr}: {e}");
+                        break;
+                    },
+                }
+            },
+            frame = received.recv() => {
+                match frame {
+                    Ok(frame) => {
+                        if let Err(e) = writer.write_all(&kiss_codec::encode(&frame)).await {
+                            warn!("KISS client {addr}: {e}");
+                            break;
+                        }
+                    },
+                    Err(broadcast::error::RecvError::Lagged(num)) => warn!("KISS client {addr}: {num} frames dropped"),
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            },
+            changed = conf_receive.changed() => {
+                if changed.is_err() || conf_receive.borrow().kiss != conf {
+                    break;
+                }
+            },
+        }
+    }
+
+    info!("KISS client {addr} disconnected");
+}
+
+// Accepts clients until an error occurs or the KISS configuration changes
+async fn serve(
+    shared_state: &SharedState,
+    conf: &config::KissConfig,
+    conf_receive: &mut watch::Receiver<config::Config>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&conf.address).await
+        .with_context(|| format!("binding KISS server to {}", conf.address))?;
+    info!("KISS server listening on {}", conf.address);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, addr) = accepted.context("accepting KISS client")?;
+                info!("KISS client {addr} connected");
+                tokio::task::spawn(handle_client(shared_state.clone(), stream, addr, conf.clone()));
+            },
+            changed = conf_receive.changed() => {
+                changed.context("Configuration channel closed")?;
+                if conf_receive.borrow().kiss != *conf {
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
+
+pub async fn run(shared_state: SharedState) {
+    let mut conf_receive = shared_state.lock().unwrap().conf.subscribe();
+
+    loop {
+        let conf = conf_receive.borrow_and_update().kiss.clone();
+
+        if !conf.enabled {
+            if conf_receive.changed().await.is_err() {
+                return;
+            }
+            continue;
+        }
+
+        match serve(&shared_state, &conf, &mut conf_receive).await {
+            Ok(()) => info!("KISS configuration changed"),
+            Err(e) => {
+                warn!("KISS server error: {e:#}");
+                tokio::select! {
+                    _ = tokio::time::sleep(RETRY_INTERVAL) => (),
+                    changed = conf_receive.changed() => if changed.is_err() {
+                        return;
+                    },
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_frames_for_port_0() {
+        assert_eq!(parse_kiss_frame(&[0x00, 1, 2, 3]), Some(KissFrame::Data(&[1, 2, 3])));
+        assert_eq!(parse_kiss_frame(&[0x00]), Some(KissFrame::Data(&[])));
+    }
+
+    #[test]
+    fn ignores_other_ports() {
+        assert_eq!(parse_kiss_frame(&[0x10, 1, 2, 3]), Some(KissFrame::OtherPort(1)));
+        assert_eq!(parse_kiss_frame(&[0xF0, 1]), Some(KissFrame::OtherPort(15)));
+    }
+
+    #[test]
+    fn ignores_commands() {
+        // TXDELAY and persistence on port 0, TXDELAY on port 1
+        assert_eq!(parse_kiss_frame(&[0x01, 50]), Some(KissFrame::Command(0x01)));
+        assert_eq!(parse_kiss_frame(&[0x02, 63]), Some(KissFrame::Command(0x02)));
+        assert_eq!(parse_kiss_frame(&[0x11, 50]), Some(KissFrame::Command(0x11)));
+        assert_eq!(parse_kiss_frame(&[]), None);
+    }
+}
@@ -0,0 +1,144 @@
+// KISS framing, as used by TNCs such as Direwolf.
+// Also used by the kiss-client tool.
+
+pub const FEND : u8 = 0xC0;
+pub const FESC : u8 = 0xDB;
+pub const TFEND : u8 = 0xDC;
+pub const TFESC : u8 = 0xDD;
+
+// Low nibble of the type byte
+pub const CMD_DATA : u8 = 0x00;
+
+// Wrap a frame in a KISS data frame for port 0
+pub fn encode(frame: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(frame.len() + 4);
+    out.push(FEND);
+    out.push(CMD_DATA);
+    for &b in frame {
+        match b {
+            FEND => out.extend_from_slice(&[FESC, TFEND]),
+            FESC => out.extend_from_slice(&[FESC, TFESC]),
+            b => out.push(b),
+        }
+    }
+    out.push(FEND);
+    out
+}
+
+/* Reassembles KISS frames from a byte stream. Each returned frame
+ * still starts with its type byte (port and command). */
+pub struct Decoder {
+    frame : Vec<u8>,
+    escaped : bool,
+    // Set when the current frame grew beyond max_len, it is dropped at the next FEND
+    overflow : bool,
+    max_len : usize,
+}
+
+impl Decoder {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            frame: Vec::new(),
+            escaped: false,
+            overflow: false,
+            max_len,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+
+        for &b in data {
+            if b == FEND {
+                if !self.frame.is_empty() && !self.overflow {
+                    frames.push(std::mem::take(&mut self.frame));
+                }
+                self.frame.clear();
+                self.escaped = false;
+                self.overflow = false;
+                continue;
+            }
+
+            let b = if self.escaped {
+                self.escaped = false;
+                match b {
+                    TFEND => FEND,
+                    TFESC => FESC,
+                    b => b,
+                }
+            }
+            else if b == FESC {
+                self.escaped = true;
+                continue;
+            }
+            else {
+                b
+            };
+
+            if self.frame.len() >= self.max_len {
+                self.overflow = true;
+            }
+            else {
+                self.frame.push(b);
+            }
+        }
+
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_with_escaped_bytes() {
+        let frame = [1, FEND, 2, FESC, 3, TFEND, TFESC, FESC, FEND];
+        let encoded = encode(&frame);
+        assert_eq!(encoded.iter().filter(|&&b| b == FEND).count(), 2);
+
+        let mut decoder = Decoder::new(64);
+        let frames = decoder.push(&encoded);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0][0], CMD_DATA);
+        assert_eq!(frames[0][1..], frame);
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        let mut stream = encode(&[1, FEND, 2]);
+        stream.extend(encode(&[3, FESC, 4]));
+
+        // Also split between an escape and the escaped byte
+        let mut decoder = Decoder::new(64);
+        let mut frames = Vec::new();
+        for chunk in stream.chunks(3) {
+            frames.extend(decoder.push(chunk));
+        }
+        assert_eq!(frames, [vec![CMD_DATA, 1, FEND, 2], vec![CMD_DATA, 3, FESC, 4]]);
+    }
+
+    #[test]
+    fn back_to_back_fends_are_ignored() {
+        let mut decoder = Decoder::new(64);
+        assert_eq!(decoder.push(&[FEND, FEND, FEND, CMD_DATA, 1, FEND]), [vec![CMD_DATA, 1]]);
+    }
+
+    #[test]
+    fn oversized_frame_is_dropped() {
+        let mut stream = encode(&[0xAA; 10]);
+        stream.extend(encode(&[1, 2]));
+
+        // Type byte and up to 4 bytes of data
+        let mut decoder = Decoder::new(5);
+        assert_eq!(decoder.push(&stream), [vec![CMD_DATA, 1, 2]]);
+    }
+
+    #[test]
+    fn resynchronises_after_oversized_frame() {
+        let mut decoder = Decoder::new(5);
+        assert!(decoder.push(&[FEND, CMD_DATA, 1, 2, 3, 4, 5, 6]).is_empty());
+        assert!(decoder.push(&[7, 8, FEND]).is_empty());
+        assert_eq!(decoder.push(&encode(&[9])), [vec![CMD_DATA, 9]]);
+    }
+}
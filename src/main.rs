@@ -1,28 +1,54 @@
-use anyhow::{anyhow, Context};
 use log::{debug, info, warn, error};
 use std::sync::{Arc, Mutex};
-use tokio::net::UdpSocket;
-use tokio::sync::{mpsc, broadcast};
-use radio::{RadioManager, MAX_PACKET_LEN};
+use tokio::sync::{mpsc, broadcast, watch};
+use radio::MAX_PACKET_LEN;
 
+mod airtime;
+mod beacon;
 mod db;
+mod dedup;
+mod digipeater;
+mod felinet;
+mod felinet_proto;
+mod framing;
+mod kiss;
+mod kiss_codec;
+mod pcap;
 mod radio;
 mod config;
+mod transmit;
+mod tunnel;
+mod tx_queue;
 mod ui;
 
 struct AppState {
-    conf : config::Config,
+    // Subsystems subscribe to it to apply configuration changes
+    conf : watch::Sender<config::Config>,
     db : db::Database,
-    transmit_queue : mpsc::Sender<Vec<u8>>,
+    transmit_queue : tx_queue::TxQueue,
     ws_broadcast : broadcast::Sender<ui::UIPacket>,
+    // Received frames, for the KISS clients
+    kiss_broadcast : broadcast::Sender<Vec<u8>>,
+    // Frames stored in the database, for the capture file
+    capture : mpsc::Sender<db::Packet>,
     start_time : chrono::DateTime<chrono::Utc>,
+    // Frequency in kHz the radio is tuned to, None if no RF radio is in use
+    frequency : Option<u32>,
+    felinet : felinet::Status,
+    radio : radio::Status,
+    csma : radio::Csma,
+    airtime : airtime::Limiter,
+    num_digipeated : u64,
 }
 
-type SharedState = Arc<Mutex<AppState>>;
+impl AppState {
+    // Current configuration
+    fn config(&self) -> config::Config {
+        self.conf.borrow().clone()
+    }
+}
 
-/* 8191 max packet size would give nearly 32 packets of size 255.
- * Let's leave some space for other whiskers too. */
-const TUN_MTU : usize = 24*255;
+type SharedState = Arc<Mutex<AppState>>;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
@@ -31,141 +57,204 @@ async fn main() -> std::io::Result<()> {
         .env()
         .init().unwrap();
 
-    let conf = config::Config::load().expect("Could not load config");
-
-    let (mut tun_sink, tun_source) = if conf.tunnel.enabled {
-        let tunnelconf = conf.tunnel.clone();
-        let mut tunconfig = tun::Configuration::default();
-
-        tunconfig
-            .address(tunnelconf.local_ip)
-            .netmask(tunnelconf.netmask)
-            .mtu(TUN_MTU.try_into().unwrap())
-            .up();
-
-        #[cfg(target_os = "linux")]
-        tunconfig.platform(|tunconfig| {
-            tunconfig.packet_information(true);
-        });
-
-        let dev = tun::create_as_async(&tunconfig).unwrap();
-        use futures::stream::StreamExt;
-        let (tun_sink, tun_source) = dev.into_framed().split();
-        (Some(tun_sink), Some(tun_source))
-    }
-    else {
-        (None, None)
+    let conf = match config::Config::load() {
+        Ok(conf) => conf,
+        Err(e) => {
+            error!("Could not load config: {e:#}");
+            std::process::exit(1);
+        },
     };
 
     let (radio_rx_queue, mut packet_receive) = mpsc::channel(16);
-    let (packet_send, mut radio_tx_queue) = mpsc::channel::<Vec<u8>>(16);
+    let transmit_queue = tx_queue::TxQueue::default();
+    let csma = radio::Csma::new(&conf.radio.csma);
+    let (tx_status, tx_status_receive) = mpsc::channel::<radio::TxStatus>(16);
+    let (frequency_control, frequency_receive) = watch::channel(conf.freq);
+    let (felinet_uplink, felinet_uplink_receive) = mpsc::channel::<Vec<u8>>(16);
+    let (tunnel_incoming, tunnel_incoming_receive) = mpsc::channel::<Vec<u8>>(16);
+    let (capture, capture_receive) = mpsc::channel::<db::Packet>(64);
 
     let shared_state = Arc::new(Mutex::new(AppState {
-        conf : conf.clone(),
+        conf : watch::Sender::new(conf.clone()),
         db : db::Database::new().await,
-        transmit_queue : packet_send.clone(),
+        transmit_queue : transmit_queue.clone(),
         ws_broadcast : broadcast::Sender::new(2),
+        kiss_broadcast : broadcast::Sender::new(16),
+        capture,
         start_time : chrono::Utc::now(),
+        frequency : None,
+        felinet : Default::default(),
+        radio : Default::default(),
+        csma : csma.clone(),
+        airtime : Default::default(),
+        num_digipeated : 0,
     }));
 
-    if conf.freq == 0 {
-        warn!("Frequency {0} is zero, disabling radio. Fake receiver udp 127.0.0.1:9073, sending to 9074", conf.freq);
-        let sock_r = Arc::new(UdpSocket::bind("127.0.0.1:9073").await?);
-        let sock_s = sock_r.clone();
-
-        // These two tasks behave like the radio, but use UDP instead of the RF channel.
-        tokio::spawn(async move {
-            let mut buf = [0; 1024];
-            while let Ok((len, addr)) = sock_r.recv_from(&mut buf).await {
-                println!("{:?} bytes received from {:?}", len, addr);
-                // Cut the length prefix, which isn't returned by the real radio
-                let packet = buf[2..len].to_vec();
-                let rssi = 0f64;
-                radio_rx_queue.send((packet, rssi)).await.expect("Inject frame");
+    // The frequency was validated when loading the configuration
+    let kind = match conf.radio.backend {
+        config::RadioBackendKind::Rf4463 if conf.freq == 0 => {
+            warn!("Frequency {0} is zero, disabling radio. Using the UDP fake radio instead", conf.freq);
+            config::RadioBackendKind::Udp
+        },
+        kind => kind,
+    };
+
+    info!("Setting up {kind:?} radio backend");
+    match radio::create_backend(kind, &conf.radio, radio_rx_queue, transmit_queue, tx_status, frequency_receive, csma).await {
+        Ok(radio) => {
+            if kind == config::RadioBackendKind::Rf4463 {
+                info!("Setting up radio on {} kHz...", conf.freq);
+                shared_state.lock().unwrap().frequency = Some(conf.freq);
             }
-        });
 
-        tokio::spawn(async move {
-            while let Some(p) = radio_tx_queue.recv().await {
-                sock_s.send_to(&p, "127.0.0.1:9074").await.unwrap();
+            shared_state.lock().unwrap().radio.backend = Some(radio.name());
+            if let Some(temperature) = radio.temperature_mutex() {
+                tokio::task::spawn(radio::monitor_temperature(shared_state.clone(), temperature));
             }
-        });
-    }
-    else if !(430000..=436380).contains(&conf.freq) {
-        error!("Frequency {} kHz out of range (430MHz - 436.375MHz), skipping radio setup", conf.freq);
+
+            tokio::task::spawn(radio::supervise(shared_state.clone(), radio));
+        },
+        // Keep the UI and database available to fix the configuration
+        Err(e) => {
+            error!("Could not initialize {kind:?} radio, continuing without radio: {e:#}");
+            shared_state.lock().unwrap().radio.error(format!("{e:#}"));
+        },
     }
-    else {
-        info!("Setting up radio");
-        let mut radio = RadioManager::new(radio_rx_queue, radio_tx_queue).expect("Could not initialize radio");
-
-        let channel = ((conf.freq - 430000) / 25) as u8;
-        radio.set_channel(channel);
-        let actual_freq = 430000 + 25 * channel as u32;
-        info!("Setting up radio on {actual_freq} kHz...");
-
-        tokio::task::spawn(async move {
-            loop {
-                if let Err(e) = radio.process_forever().await {
-                    error!("Radio error: {e}")
+
+    // The backend follows the frequency of the configuration
+    let shared_state_frequency = shared_state.clone();
+    tokio::task::spawn(async move {
+        let mut conf_receive = shared_state_frequency.lock().unwrap().conf.subscribe();
+        while conf_receive.changed().await.is_ok() {
+            let freq = conf_receive.borrow_and_update().freq;
+
+            // A zero frequency, which disables the RF radio, is only applied at startup
+            if freq == 0 {
+                continue;
+            }
+
+            if frequency_control.send_if_modified(|f| std::mem::replace(f, freq) != freq) {
+                let mut st = shared_state_frequency.lock().unwrap();
+                if st.frequency.is_some() {
+                    st.frequency = Some(freq);
                 }
             }
-        });
-    }
+        }
+    });
+
+    // Channel access follows the configuration too, the backend shares the parameters
+    let shared_state_csma = shared_state.clone();
+    tokio::task::spawn(async move {
+        let (mut conf_receive, csma) = {
+            let st = shared_state_csma.lock().unwrap();
+            (st.conf.subscribe(), st.csma.clone())
+        };
+        while conf_receive.changed().await.is_ok() {
+            let conf = conf_receive.borrow_and_update().radio.csma.clone();
+            csma.set_config(&conf);
+        }
+    });
 
     let shared_state_receive = shared_state.clone();
     tokio::task::spawn(async move {
+        let mut duplicates = dedup::DuplicateDetector::new();
+
         while let Some((packet_data, rssi)) = packet_receive.recv().await {
             debug!("RX RSSI {} len {}", rssi, packet_data.len());
+            shared_state_receive.lock().unwrap().radio.frame_received();
+
             let mut buf = [0; MAX_PACKET_LEN];
             match ham_cats::packet::Packet::fully_decode(&packet_data, &mut buf) {
                 Ok(packet) => {
-                    let (mut db, ws_broadcast) = {
+                    let (conf, mut db, ws_broadcast, kiss_broadcast, capture, frequency) = {
                         let g = shared_state_receive.lock().unwrap();
-                        (g.db.clone(), g.ws_broadcast.clone())
+                        (g.config(), g.db.clone(), g.ws_broadcast.clone(), g.kiss_broadcast.clone(), g.capture.clone(), g.frequency)
                     };
 
                     if let Some(ident) = packet.identification() {
                         debug!(" From {}-{}", ident.callsign, ident.ssid);
+                    }
 
-                        let mut commentbuf = [0u8; 255];
-                        match packet.comment(&mut commentbuf) {
-                            Ok(comment) => {
-                                let m = ui::UIPacket {
-                                    received_at: chrono::Utc::now(),
-                                    from_callsign: ident.callsign.to_string(),
-                                    from_ssid: ident.ssid,
-                                    comment: Some(comment.to_owned())
-                                };
+                    let key = dedup::content_key(&packet_data);
+                    let mut metadata = db::FrameMetadata::received(rssi, frequency);
+                    metadata.duplicate_of = key.and_then(|k| duplicates.original_of(k));
+
+                    match db.store_packet(&packet_data, &metadata).await {
+                        Ok(id) => {
+                            if let (Some(k), None) = (key, metadata.duplicate_of) {
+                                duplicates.insert(k, id);
+                            }
+
+                            let stored = db::Packet::new(id, packet_data.clone(), &metadata);
+                            if let Some(m) = ui::UIPacket::from_db_packet(&stored) {
                                 match ws_broadcast.send(m) {
                                     Ok(num) => debug!("Send WS message to {num}"),
                                     Err(_) => debug!("No WS receivers currently"),
                                 }
                             }
-                            Err(e) => warn!("Decode packet comment error: {e}"),
-                        }
+
+                            if capture.try_send(stored).is_err() {
+                                warn!("Capture queue full, dropping frame");
+                            }
+                        },
+                        Err(e) => warn!("Failed to write to sqlite: {}", e),
+                    }
+
+                    // Like a TNC, pass every frame heard to the KISS clients, duplicates included
+                    if let Ok(num) = kiss_broadcast.send(packet_data.clone()) {
+                        debug!("Send frame to {num} KISS clients");
+                    }
+
+                    if let Some(original) = metadata.duplicate_of {
+                        debug!(" Duplicate of {original}");
+                        continue;
                     }
 
-                    if let Err(e) = db.store_packet(&packet_data).await {
-                        warn!("Failed to write to sqlite: {}", e);
+                    if felinet_uplink.try_send(packet_data.clone()).is_err() {
+                        warn!("FELINET uplink queue full, dropping frame");
                     }
 
-                    if let Some(sink) = &mut tun_sink {
+                    match digipeater::digipeat(&conf, &packet_data, rssi) {
+                        Ok(Some(frame)) => {
+                            debug!("Digipeating {} bytes", frame.len());
+                            match transmit::queue(&shared_state_receive, frame, db::TxSource::Digipeater).await {
+                                Ok(_) => shared_state_receive.lock().unwrap().num_digipeated += 1,
+                                Err(e) => warn!("Failed to queue digipeated packet: {e}"),
+                            }
+                        },
+                        Ok(None) => (),
+                        Err(e) => warn!("Digipeater error: {e}"),
+                    }
+
+                    if conf.tunnel.enabled {
                         let mut incoming = Vec::new();
                         for arb in packet.arbitrary_iter() {
                             incoming.extend_from_slice(arb.0.as_slice());
                         }
 
-                        if !incoming.is_empty() {
-                            use futures::SinkExt;
-                            if let Err(e) = sink.send(tun::TunPacket::new(incoming)).await {
-                                warn!("Failed to send to TUN: {}", e);
-                            }
+                        if !incoming.is_empty() && tunnel_incoming.try_send(incoming).is_err() {
+                            warn!("Tunnel queue full, dropping packet");
                         }
                     }
                 }
                 Err(e) => {
                     warn!("Failed to decode packet: {}", e);
-                    eprintln!("{:02X?}", packet_data);
+                    debug!("{:02X?}", packet_data);
+
+                    let (mut db, capture, frequency) = {
+                        let g = shared_state_receive.lock().unwrap();
+                        (g.db.clone(), g.capture.clone(), g.frequency)
+                    };
+
+                    let metadata = db::FrameMetadata::undecodable(rssi, frequency, e.to_string());
+                    match db.store_packet(&packet_data, &metadata).await {
+                        Ok(id) => {
+                            if capture.try_send(db::Packet::new(id, packet_data, &metadata)).is_err() {
+                                warn!("Capture queue full, dropping frame");
+                            }
+                        },
+                        Err(e) => warn!("Failed to write undecodable frame to sqlite: {}", e),
+                    }
                 }
             }
         }
@@ -173,54 +262,14 @@ async fn main() -> std::io::Result<()> {
         warn!("Packet receive task stopping");
     });
 
-    let shared_state_tunnel = shared_state.clone();
-    if let Some(mut source) = tun_source {
-        tokio::task::spawn(async move {
-            use futures::stream::StreamExt;
-            while let Some(packet_from_tun) = source.next().await {
-                match packet_from_tun {
-                    Ok(ip_packet) if ip_packet.get_bytes().len() <= TUN_MTU => {
-                        println!("RX: {} bytes", ip_packet.get_bytes().len());
-
-                        let config = shared_state_tunnel.lock().unwrap().conf.clone();
-
-                        fn build_tun_packet(config: config::Config, ip_packet: &[u8]) -> anyhow::Result<Vec<u8>> {
-                            let mut buf = [0; MAX_PACKET_LEN];
-                            let mut pkt = ham_cats::packet::Packet::new(&mut buf);
-                            pkt.add_identification(
-                                ham_cats::whisker::Identification::new(&config.callsign, config.ssid, config.icon)
-                                .context("Invalid identification")?
-                                ).map_err(|e| anyhow!("Could not add identification to packet: {e}"))?;
-
-                            for part in ip_packet.chunks(255) {
-                                pkt.add_arbitrary(ham_cats::whisker::Arbitrary::new(part).unwrap())
-                                    .map_err(|e| anyhow!("Could not add data to packet: {e}"))?;
-                            }
-
-                            let mut buf2 = [0; MAX_PACKET_LEN];
-                            let mut data = ham_cats::buffer::Buffer::new_empty(&mut buf2);
-                            pkt.fully_encode(&mut data)
-                                .map_err(|e| anyhow!("Could not encode packet: {e}"))?;
-                            Ok(data.to_vec())
-                        }
-
-                        match build_tun_packet(config, ip_packet.get_bytes()) {
-                            Ok(data) => if let Err(e) = packet_send.send(data).await {
-                                warn!("Failed to send TUN packet: {e}");
-                            },
-                            Err(e) => warn!("Failed to prepare TUN packet: {e}"),
-                        }
-                    },
-                    Ok(ip_packet) => {
-                        println!("RX: too large packet: {} bytes", ip_packet.get_bytes().len());
-                    },
-                    Err(err) => panic!("Error: {:?}", err),
-                }
-            }
-        });
-    }
+    tokio::task::spawn(transmit::run(shared_state.clone(), tx_status_receive));
+    tokio::task::spawn(beacon::run(shared_state.clone()));
+    tokio::task::spawn(felinet::run(shared_state.clone(), felinet_uplink_receive));
+    tokio::task::spawn(kiss::run(shared_state.clone()));
+    tokio::task::spawn(tunnel::run(shared_state.clone(), tunnel_incoming_receive));
+    tokio::task::spawn(pcap::run(shared_state.clone(), capture_receive));
 
-    let port = 3000;
+    let port = conf.ui_port;
     info!("Setting up listener on port {port}");
     ui::serve(port, shared_state).await;
     Ok(())
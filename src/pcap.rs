@@ -0,0 +1,326 @@
+use std::time::Duration;
+use anyhow::Context;
+use log::{info, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, watch};
+
+use crate::{config, db, SharedState};
+
+/* Frames in the pcapng format, for Wireshark. CATS has no link type of its own,
+ * frames are written as LINKTYPE_USER0, as they are stored in the database.
+ * The direction is in the flags of every packet, the RSSI, the frequency and
+ * the decoding status in its comment. */
+const LINKTYPE_USER0 : u16 = 147;
+
+const BLOCK_SECTION_HEADER : u32 = 0x0A0D0D0A;
+const BLOCK_INTERFACE_DESCRIPTION : u32 = 1;
+const BLOCK_ENHANCED_PACKET : u32 = 6;
+const BYTE_ORDER_MAGIC : u32 = 0x1A2B3C4D;
+
+const OPT_END : u16 = 0;
+const OPT_COMMENT : u16 = 1;
+const SHB_USERAPPL : u16 = 4;
+const IF_NAME : u16 = 2;
+const EPB_FLAGS : u16 = 2;
+
+const EPB_FLAG_INBOUND : u32 = 1;
+const EPB_FLAG_OUTBOUND : u32 = 2;
+
+const RETRY_INTERVAL : Duration = Duration::from_secs(10);
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+fn block(block_type: u32, mut body: Vec<u8>) -> Vec<u8> {
+    push_option(&mut body, OPT_END, &[]);
+    let total_len = (body.len() + 12) as u32;
+
+    let mut out = Vec::with_capacity(total_len as usize);
+    out.extend_from_slice(&block_type.to_le_bytes());
+    out.extend_from_slice(&total_len.to_le_bytes());
+    out.extend_from_slice(&body);
+    out.extend_from_slice(&total_len.to_le_bytes());
+    out
+}
+
+// Section header and the description of the radio interface, which start every file
+pub fn header() -> Vec<u8> {
+    let mut shb = Vec::new();
+    shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    shb.extend_from_slice(&1u16.to_le_bytes());
+    shb.extend_from_slice(&0u16.to_le_bytes());
+    // Section length not specified
+    shb.extend_from_slice(&(-1i64).to_le_bytes());
+    push_option(&mut shb, SHB_USERAPPL, b"cats-radio-node");
+
+    let mut idb = Vec::new();
+    idb.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+    idb.extend_from_slice(&0u16.to_le_bytes());
+    // No snapshot length limit
+    idb.extend_from_slice(&0u32.to_le_bytes());
+    push_option(&mut idb, IF_NAME, b"cats");
+
+    let mut out = block(BLOCK_SECTION_HEADER, shb);
+    out.extend(block(BLOCK_INTERFACE_DESCRIPTION, idb));
+    out
+}
+
+fn comment(packet: &db::Packet) -> String {
+    let mut parts = vec![format!("frame {}", packet.id)];
+    if let Some(rssi) = packet.rssi {
+        parts.push(format!("RSSI {rssi:.1} dBm"));
+    }
+    if let Some(freq) = packet.freq {
+        parts.push(format!("{freq} kHz"));
+    }
+    if let Some(original) = packet.duplicate_of {
+        parts.push(format!("duplicate of {original}"));
+    }
+    if let Some(e) = &packet.decode_error {
+        parts.push(format!("undecodable: {e}"));
+    }
+    parts.join(", ")
+}
+
+pub fn packet_block(packet: &db::Packet) -> Vec<u8> {
+    // Microseconds since the epoch, the default resolution
+    let timestamp = packet.received_at.timestamp_micros() as u64;
+    let len = packet.content.len() as u32;
+
+    let mut epb = Vec::new();
+    // Interface id
+    epb.extend_from_slice(&0u32.to_le_bytes());
+    epb.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    epb.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    epb.extend_from_slice(&len.to_le_bytes());
+    epb.extend_from_slice(&len.to_le_bytes());
+    epb.extend_from_slice(&packet.content);
+    epb.resize(epb.len().next_multiple_of(4), 0);
+
+    let flags = match packet.direction {
+        db::Direction::Received => EPB_FLAG_INBOUND,
+        db::Direction::Transmitted => EPB_FLAG_OUTBOUND,
+    };
+    push_option(&mut epb, EPB_FLAGS, &flags.to_le_bytes());
+    push_option(&mut epb, OPT_COMMENT, comment(packet).as_bytes());
+
+    block(BLOCK_ENHANCED_PACKET, epb)
+}
+
+// A complete capture of the given frames
+pub fn export(packets: &[db::Packet]) -> Vec<u8> {
+    let mut out = header();
+    for packet in packets {
+        out.extend(packet_block(packet));
+    }
+    out
+}
+
+/* Capture file that is moved to <path>.1 when it is full, shifting the older
+ * files to <path>.2 and so on. A capture left by a previous run is kept the
+ * same way. */
+struct RollingFile {
+    conf : config::CaptureConfig,
+    file : tokio::fs::File,
+    size : u64,
+}
+
+impl RollingFile {
+    async fn open(conf: &config::CaptureConfig) -> anyhow::Result<Self> {
+        shift_files(conf).await?;
+
+        let mut file = tokio::fs::File::create(&conf.path).await
+            .with_context(|| format!("creating capture file {}", conf.path))?;
+        let header = header();
+        file.write_all(&header).await?;
+
+        Ok(Self {
+            conf: conf.clone(),
+            file,
+            size: header.len() as u64,
+        })
+    }
+
+    async fn write(&mut self, packet: &db::Packet) -> anyhow::Result<()> {
+        let block = packet_block(packet);
+        let max_size = u64::from(self.conf.max_file_size_kb) * 1024;
+        if self.size + block.len() as u64 > max_size {
+            self.file.flush().await?;
+            *self = Self::open(&self.conf).await?;
+        }
+
+        self.file.write_all(&block).await
+            .with_context(|| format!("writing to capture file {}", self.conf.path))?;
+        self.file.flush().await?;
+        self.size += block.len() as u64;
+        Ok(())
+    }
+}
+
+async fn shift_files(conf: &config::CaptureConfig) -> anyhow::Result<()> {
+    let rotated = |n: u32| if n == 0 { conf.path.clone() } else { format!("{}.{n}", conf.path) };
+
+    // The oldest file is replaced by the one before it
+    for n in (1..conf.max_files).rev() {
+        match tokio::fs::rename(rotated(n - 1), rotated(n)).await {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e).with_context(|| format!("moving capture file {}", rotated(n - 1))),
+        }
+    }
+    Ok(())
+}
+
+// Writes the frames until an error occurs or the capture configuration changes
+async fn capture(
+    conf: &config::CaptureConfig,
+    frames: &mut mpsc::Receiver<db::Packet>,
+    conf_receive: &mut watch::Receiver<config::Config>) -> anyhow::Result<()> {
+    let mut file = RollingFile::open(conf).await?;
+    info!("Capturing frames to {}", conf.path);
+
+    loop {
+        tokio::select! {
+            packet = frames.recv() => {
+                let packet = packet.context("Capture channel closed")?;
+                file.write(&packet).await?;
+            },
+            changed = conf_receive.changed() => {
+                changed.context("Configuration channel closed")?;
+                if conf_receive.borrow().capture != *conf {
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
+
+/* Writes the frames received and transmitted to the capture file, if enabled.
+ * Frames are stored in the database in any case. */
+pub async fn run(shared_state: SharedState, mut frames: mpsc::Receiver<db::Packet>) {
+    let mut conf_receive = shared_state.lock().unwrap().conf.subscribe();
+
+    loop {
+        let conf = conf_receive.borrow_and_update().capture.clone();
+
+        if !conf.enabled {
+            // Discard the frames until the capture is enabled
+            tokio::select! {
+                packet = frames.recv() => if packet.is_none() {
+                    return;
+                },
+                changed = conf_receive.changed() => if changed.is_err() {
+                    return;
+                },
+            }
+            continue;
+        }
+
+        match capture(&conf, &mut frames, &mut conf_receive).await {
+            Ok(()) => info!("Capture configuration changed"),
+            Err(e) => {
+                warn!("Capture error: {e:#}");
+                tokio::select! {
+                    _ = tokio::time::sleep(RETRY_INTERVAL) => (),
+                    changed = conf_receive.changed() => if changed.is_err() {
+                        return;
+                    },
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    // Splits a capture into the type and body of its blocks, checking their lengths
+    fn blocks(mut data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            let total_len = u32_at(data, 4) as usize;
+            assert_eq!(total_len % 4, 0);
+            assert_eq!(u32_at(data, total_len - 4) as usize, total_len, "trailing block length");
+            out.push((u32_at(data, 0), &data[8..total_len - 4]));
+            data = &data[total_len..];
+        }
+        out
+    }
+
+    fn temporary_capture_config(name: &str, max_files: u32) -> config::CaptureConfig {
+        let dir = std::env::temp_dir().join(format!("cats-radio-node-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        config::CaptureConfig {
+            enabled: true,
+            path: dir.join("capture.pcapng").to_str().unwrap().to_owned(),
+            max_file_size_kb: 1,
+            max_files,
+        }
+    }
+
+    #[test]
+    fn frames_are_padded_to_32_bits() {
+        let frames = [vec![0xAA; 5], vec![0xBB; 7]];
+        let packets : Vec<_> = frames.iter().enumerate()
+            .map(|(id, frame)| db::Packet::new(id as i64, frame.clone(), &db::FrameMetadata::received(-90.0, Some(430500))))
+            .collect();
+
+        let capture = export(&packets);
+        let blocks = blocks(&capture);
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[0].0, BLOCK_SECTION_HEADER);
+        assert_eq!(blocks[1].0, BLOCK_INTERFACE_DESCRIPTION);
+
+        for ((block_type, body), frame) in blocks[2..].iter().zip(&frames) {
+            assert_eq!(*block_type, BLOCK_ENHANCED_PACKET);
+            // Captured and original lengths
+            assert_eq!(u32_at(body, 12) as usize, frame.len());
+            assert_eq!(u32_at(body, 16) as usize, frame.len());
+
+            let data = &body[20..];
+            assert_eq!(&data[..frame.len()], frame.as_slice());
+            let padded_len = frame.len().next_multiple_of(4);
+            assert!(data[frame.len()..padded_len].iter().all(|&b| b == 0));
+            // The flags option follows the padding
+            assert_eq!(&data[padded_len..padded_len + 8], [2, 0, 4, 0, EPB_FLAG_INBOUND as u8, 0, 0, 0]);
+        }
+    }
+
+    #[tokio::test]
+    async fn rollover_keeps_max_files() {
+        let conf = temporary_capture_config("rollover", 3);
+        let metadata = db::FrameMetadata::transmitted(None);
+
+        // Blocks of about 100 bytes, several files worth with 1 kB per file
+        let mut file = RollingFile::open(&conf).await.unwrap();
+        for id in 0..50 {
+            file.write(&db::Packet::new(id, vec![0x55; 31], &metadata)).await.unwrap();
+        }
+
+        let dir = std::path::Path::new(&conf.path).parent().unwrap();
+        let mut names : Vec<_> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["capture.pcapng", "capture.pcapng.1", "capture.pcapng.2"]);
+
+        for name in names {
+            let data = std::fs::read(dir.join(name)).unwrap();
+            assert!(data.len() <= 1024);
+            assert!(data.starts_with(&header()));
+            assert!(blocks(&data).len() > 2);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,162 +1,228 @@
-use anyhow::{anyhow, bail, Context};
-use rand::{thread_rng, Rng};
-use rf4463::{config::RADIO_CONFIG_CATS, Rf4463};
-use rppal::{
-    gpio::{Gpio, OutputPin},
-    hal::Delay,
-    spi::{Bus, Mode, SlaveSelect, Spi},
-};
+use chrono::serde::{ts_seconds, ts_seconds_option};
+use futures::future::BoxFuture;
+use log::{error, info, warn};
+use serde::Serialize;
 use std::{
+    collections::VecDeque,
     sync::Arc,
-    time::{Duration, Instant},
+    time::Duration,
 };
 use tokio::sync::{
-    mpsc::{error::TryRecvError, Receiver, Sender},
+    mpsc::Sender,
+    watch,
     Mutex,
 };
 
+use crate::{config, db::TxState, tx_queue::TxQueue, SharedState};
+
+pub mod csma;
+mod loopback;
+mod rf4463_hat;
+mod udp;
+
+pub use csma::Csma;
+pub use loopback::LoopbackRadio;
+pub use rf4463_hat::RadioManager;
+pub use udp::UdpRadio;
+
 pub const MAX_PACKET_LEN: usize = 8191;
 
-pub struct RadioManager {
-    radio: Rf4463<Spi, OutputPin, OutputPin, Delay>,
+const TEMPERATURE_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+// Two hours of temperature history
+const TEMPERATURE_HISTORY_LEN: usize = 120;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// Consecutive failures after which the radio is considered lost
+const ALARM_AFTER_FAILURES: u32 = 5;
+// A backend running that long without error has recovered
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TemperatureSample {
+    #[serde(with = "ts_seconds")]
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub celsius: f32,
+}
 
-    receive_queue: Sender<(Vec<u8>, f64)>,
-    transmit_queue: Receiver<Vec<u8>>,
-    rx_buf: [u8; MAX_PACKET_LEN],
-    temperature: Arc<Mutex<f32>>,
+/// Radio health, shown on the dashboard and in /api/radio
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Status {
+    /// Name of the backend in use, None without radio
+    pub backend: Option<&'static str>,
+    pub temperature: Option<f32>,
+    pub temperature_history: VecDeque<TemperatureSample>,
+    pub num_received: u64,
+    pub num_transmitted: u64,
+    pub num_tx_errors: u64,
+    pub num_errors: u64,
+    pub last_error: Option<String>,
+    /// Set while the radio could not be recovered
+    pub alarm: Option<String>,
+    #[serde(with = "ts_seconds_option")]
+    pub last_received_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
-impl RadioManager {
-    pub fn new(receive_queue: Sender<(Vec<u8>, f64)>, transmit_queue: Receiver<Vec<u8>>) -> anyhow::Result<Self> {
-        let spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0, 1_000_000, Mode::Mode0)?;
-        let gpio = Gpio::new()?;
-        let sdn = gpio.get(22)?.into_output();
-        let cs = gpio.get(24)?.into_output();
-
-        let delay = Delay::new();
-
-        let mut radio = Rf4463::new(spi, sdn, cs, delay, &mut RADIO_CONFIG_CATS.clone())
-            .map_err(|e| anyhow!("{e:?}"))?;
-        radio.set_channel(20);
-
-        let rx_buf = [0; MAX_PACKET_LEN];
-        let temperature = Arc::new(Mutex::new(radio.get_temp()?));
-
-        Ok(Self {
-            radio,
-            receive_queue,
-            transmit_queue,
-            rx_buf,
-            temperature,
-        })
+impl Status {
+    pub fn frame_received(&mut self) {
+        self.num_received += 1;
+        self.last_received_at = Some(chrono::Utc::now());
     }
 
-    pub fn set_channel(&mut self, channel: u8) {
-        self.radio.set_channel(channel);
+    pub fn error(&mut self, error: String) {
+        self.num_errors += 1;
+        self.last_error = Some(error);
     }
 
-    #[allow(dead_code)]
-    pub fn temperature_mutex(&self) -> Arc<Mutex<f32>> {
-        self.temperature.clone()
+    fn add_temperature(&mut self, celsius: f32) {
+        self.temperature = Some(celsius);
+        if self.temperature_history.len() == TEMPERATURE_HISTORY_LEN {
+            self.temperature_history.pop_front();
+        }
+        self.temperature_history.push_back(TemperatureSample { time: chrono::Utc::now(), celsius });
     }
+}
 
-    pub async fn process_forever(&mut self) -> anyhow::Result<()> {
-        loop {
-            self.tick().await?;
+/// Copy the temperature measured by the backend to the radio status
+pub async fn monitor_temperature(shared_state: SharedState, temperature: Arc<Mutex<f32>>) {
+    let mut interval = tokio::time::interval(TEMPERATURE_SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let celsius = *temperature.lock().await;
+        shared_state.lock().unwrap().radio.add_temperature(celsius);
+    }
+}
 
-            *self.temperature.lock().await = self.radio.get_temp()?;
+/// A frame to transmit, identified by its row in the transmissions table
+#[derive(Debug)]
+pub struct TxFrame {
+    pub id: i64,
+    /// Fully encoded frame, including the length prefix
+    pub data: Vec<u8>,
+}
 
-            match self.transmit_queue.try_recv() {
-                Ok(pkt) => {
-                    self.tx(&pkt).await?;
-                }
-                Err(TryRecvError::Empty) => {}
-                Err(TryRecvError::Disconnected) => {
-                    bail!("TX channel disconnected")
-                }
-            }
+/// Progress of a transmission, reported by the backend
+#[derive(Debug)]
+pub struct TxStatus {
+    pub id: i64,
+    pub state: TxState,
+    pub error: Option<String>,
+}
 
-            tokio::time::sleep(Duration::from_millis(25)).await;
-        }
+#[derive(Debug)]
+struct TxTimeout;
+
+impl std::fmt::Display for TxTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Timeout while transmitting")
     }
+}
 
-    async fn tick(&mut self) -> anyhow::Result<()> {
-        if self.radio.is_idle() {
-            self.radio
-                .start_rx(None, false)
-                .map_err(|e| anyhow!("{e}"))?;
+impl std::error::Error for TxTimeout {}
 
-            tokio::time::sleep(Duration::from_millis(25)).await;
-        }
-
-        self.radio
-            .interrupt(Some(&mut self.rx_buf), None)
-            .map_err(|e| anyhow!("{e:?}"))?;
-
-        if let Some(data) = self
-            .radio
-            .finish_rx(&mut self.rx_buf)
-            .map_err(|e| anyhow!("{e}"))?
-        {
-            self.radio
-                .start_rx(None, false)
-                .map_err(|e| anyhow!("{e}"))?;
-
-            self.receive_queue
-                .send((data.data().to_vec(), data.rssi()))
-                .await
-                .ok()
-                .context("RX channel died")?;
-        }
+async fn report_status(status_queue: &Sender<TxStatus>, id: i64, state: TxState, error: Option<String>) {
+    if status_queue.send(TxStatus { id, state, error }).await.is_err() {
+        log::warn!("TX status channel closed, transmission {id} is now {}", state.as_str());
+    }
+}
 
-        Ok(())
+/// Report the outcome of a transmission
+async fn report_result(status_queue: &Sender<TxStatus>, id: i64, result: &anyhow::Result<()>) {
+    match result {
+        Ok(()) => report_status(status_queue, id, TxState::Sent, None).await,
+        Err(e) if e.is::<TxTimeout>() => report_status(status_queue, id, TxState::TimedOut, Some(e.to_string())).await,
+        Err(e) => report_status(status_queue, id, TxState::Failed, Some(e.to_string())).await,
     }
+}
 
-    async fn tx(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        // ensures we don't tx over a packet,
-        // and adds some random delay so that every node
-        // if offset slightly
-        self.tx_delay().await?;
+/* Run the backend, resetting it with an exponential backoff when it fails.
+ * The transmit queue belongs to the backend and is kept across resets, so
+ * only the frame being sent when the error occurred is lost. */
+pub async fn supervise(shared_state: SharedState, mut radio: Box<dyn RadioBackend>) {
+    let name = radio.name();
+    let mut backoff = MIN_BACKOFF;
+    let mut failures = 0;
+
+    loop {
+        let result = {
+            let mut process = radio.process_forever();
+            tokio::select! {
+                result = &mut process => result,
+                _ = tokio::time::sleep(HEALTHY_AFTER) => {
+                    if failures > 0 {
+                        info!("Radio {} recovered", name);
+                        shared_state.lock().unwrap().radio.alarm = None;
+                    }
+                    failures = 0;
+                    backoff = MIN_BACKOFF;
+                    process.await
+                },
+            }
+        };
 
-        self.radio.start_tx(data).map_err(|e| anyhow!("{e:?}"))?;
+        let mut e = result.err().unwrap_or_else(|| anyhow::anyhow!("Radio stopped"));
+        loop {
+            failures += 1;
+            warn!("Radio {} error: {e:#}, resetting in {}s", name, backoff.as_secs());
+            {
+                let mut s = shared_state.lock().unwrap();
+                s.radio.error(format!("{e:#}"));
+                if failures == ALARM_AFTER_FAILURES {
+                    error!("Radio {} could not be recovered after {failures} attempts", name);
+                    s.radio.alarm = Some(format!("The radio failed {failures} times in a row, last error: {e:#}"));
+                }
+            }
 
-        const TIMEOUT: Duration = Duration::from_secs(10);
-        let start_time = Instant::now();
-        while !self.radio.is_idle() {
-            self.radio
-                .interrupt(None, Some(data))
-                .map_err(|e| anyhow!("{e:?}"))?;
+            tokio::time::sleep(backoff).await;
+            backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
 
-            if start_time + TIMEOUT < Instant::now() {
-                bail!("Timeout while transmitting");
+            match radio.reset().await {
+                Ok(()) => {
+                    info!("Radio {} reset", name);
+                    break;
+                },
+                Err(reset_error) => e = reset_error.context("reset failed"),
             }
-
-            tokio::time::sleep(Duration::from_millis(25)).await;
         }
-
-        Ok(())
     }
+}
 
-    async fn tx_delay(&mut self) -> anyhow::Result<()> {
-        loop {
-            let delay_ms = thread_rng().gen_range(0..50);
-
-            // since delay_ms < 100 we can safely sleep without calling tick
-            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
-
-            let mut rx = false;
+/// A transceiver able to carry CATS frames.
+///
+/// Backends push every received frame together with its RSSI into the receive queue
+/// they were created with, and transmit the frames they take from the transmit queue.
+/// The progress of every transmission is reported on the status queue, and
+/// backends follow the frequency (in kHz) published on their frequency channel.
+pub trait RadioBackend: Send {
+    /// Short name used in the logs
+    fn name(&self) -> &'static str;
+
+    /// Shared transceiver temperature, for backends that can measure it
+    fn temperature_mutex(&self) -> Option<Arc<Mutex<f32>>> {
+        None
+    }
 
-            while self.radio.is_busy_rxing()? {
-                rx = true;
-                self.tick().await?;
+    /// Bring the transceiver back to a working state after an error
+    fn reset(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
 
-                tokio::time::sleep(Duration::from_millis(25)).await;
-            }
+    /// Receive and transmit frames until an error occurs
+    fn process_forever(&mut self) -> BoxFuture<'_, anyhow::Result<()>>;
+}
 
-            if !rx {
-                // didn't rx a packet, so we're safe to leave
-                break Ok(());
-            }
-        }
-    }
+/// Create the backend selected in the `[radio]` section of the configuration
+pub async fn create_backend(
+    kind: config::RadioBackendKind,
+    conf: &config::RadioConfig,
+    receive_queue: Sender<(Vec<u8>, f64)>,
+    transmit_queue: TxQueue,
+    status_queue: Sender<TxStatus>,
+    frequency: watch::Receiver<u32>,
+    csma: Csma) -> anyhow::Result<Box<dyn RadioBackend>> {
+    Ok(match kind {
+        config::RadioBackendKind::Rf4463 => Box::new(RadioManager::new(conf, receive_queue, transmit_queue, status_queue, frequency, csma)?),
+        config::RadioBackendKind::Udp => Box::new(UdpRadio::new(conf, receive_queue, transmit_queue, status_queue, frequency, csma).await?),
+        config::RadioBackendKind::Loopback => Box::new(LoopbackRadio::new(receive_queue, transmit_queue, status_queue)),
+    })
 }
@@ -0,0 +1,177 @@
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+use crate::config;
+
+/// How often the channel was found busy, shown on the dashboard
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Stats {
+    /// Frames that went through channel access
+    pub num_frames: u64,
+    /// Frames for which the channel was busy at least once
+    pub num_busy: u64,
+    /// Slots waited, because the channel was busy or the persistence draw failed
+    pub num_deferred_slots: u64,
+    /// Frames sent anyway after the maximum deferral
+    pub num_forced: u64,
+}
+
+/// p-persistent CSMA. The parameters are shared by the backends and follow
+/// the configuration, the statistics are shared with the UI.
+#[derive(Clone)]
+pub struct Csma {
+    conf: Arc<Mutex<config::CsmaConfig>>,
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl Csma {
+    pub fn new(conf: &config::CsmaConfig) -> Self {
+        Self {
+            conf: Arc::new(Mutex::new(conf.clone())),
+            stats: Default::default(),
+        }
+    }
+
+    /// Applies to the next slot, also for the frame waiting for the channel
+    pub fn set_config(&self, conf: &config::CsmaConfig) {
+        *self.conf.lock().unwrap() = conf.clone();
+    }
+
+    fn config(&self) -> config::CsmaConfig {
+        self.conf.lock().unwrap().clone()
+    }
+
+    pub fn slot_time(&self) -> Duration {
+        Duration::from_millis(self.config().slot_time_ms.into())
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Start channel access for a frame
+    pub fn deferral(&self) -> Deferral {
+        Deferral {
+            csma: self.clone(),
+            started: Instant::now(),
+            found_busy: false,
+            slots: 0,
+        }
+    }
+}
+
+/// Channel access for one frame. The backend senses the channel once per slot
+/// and transmits as soon as [`Deferral::clear_to_send`] returns true.
+pub struct Deferral {
+    csma: Csma,
+    started: Instant,
+    found_busy: bool,
+    slots: u64,
+}
+
+impl Deferral {
+    pub fn clear_to_send(&mut self, busy: bool) -> bool {
+        self.clear_to_send_with(busy, &mut thread_rng())
+    }
+
+    fn clear_to_send_with<R: Rng>(&mut self, busy: bool, rng: &mut R) -> bool {
+        let conf = self.csma.config();
+        let max_deferral = Duration::from_millis(conf.max_deferral_ms.into());
+        if self.started.elapsed() >= max_deferral {
+            self.finish(true);
+            return true;
+        }
+
+        if busy {
+            self.found_busy = true;
+        }
+        // Transmit with probability (persistence + 1) / 256, like KISS TNCs
+        else if rng.gen::<u8>() <= conf.persistence {
+            self.finish(false);
+            return true;
+        }
+
+        self.slots += 1;
+        false
+    }
+
+    fn finish(&self, forced: bool) {
+        let mut stats = self.csma.stats.lock().unwrap();
+        stats.num_frames += 1;
+        stats.num_busy += u64::from(self.found_busy);
+        stats.num_deferred_slots += self.slots;
+        stats.num_forced += u64::from(forced);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn csma(persistence: u8) -> Csma {
+        Csma::new(&config::CsmaConfig {
+            slot_time_ms: 10,
+            persistence,
+            max_deferral_ms: 100,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_right_away_with_full_persistence() {
+        let csma = csma(255);
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..10 {
+            assert!(csma.deferral().clear_to_send_with(false, &mut rng));
+        }
+
+        let stats = csma.stats();
+        assert_eq!((stats.num_frames, stats.num_busy, stats.num_deferred_slots, stats.num_forced), (10, 0, 0, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forces_transmission_after_max_deferral() {
+        let csma = csma(0);
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut deferral = csma.deferral();
+        for _ in 0..9 {
+            assert!(!deferral.clear_to_send_with(false, &mut rng));
+            tokio::time::advance(csma.slot_time()).await;
+        }
+        tokio::time::advance(csma.slot_time()).await;
+        assert!(deferral.clear_to_send_with(false, &mut rng));
+
+        let stats = csma.stats();
+        assert_eq!((stats.num_frames, stats.num_busy, stats.num_deferred_slots, stats.num_forced), (1, 0, 9, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn defers_while_busy() {
+        let csma = csma(255);
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut deferral = csma.deferral();
+        assert!(!deferral.clear_to_send_with(true, &mut rng));
+        assert!(!deferral.clear_to_send_with(true, &mut rng));
+        assert!(deferral.clear_to_send_with(false, &mut rng));
+
+        let stats = csma.stats();
+        assert_eq!((stats.num_frames, stats.num_busy, stats.num_deferred_slots, stats.num_forced), (1, 1, 2, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_configuration_changes() {
+        let csma = csma(0);
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut deferral = csma.deferral();
+        assert!(!deferral.clear_to_send_with(false, &mut rng));
+
+        csma.set_config(&config::CsmaConfig { slot_time_ms: 50, persistence: 255, max_deferral_ms: 100 });
+        assert_eq!(csma.slot_time(), Duration::from_millis(50));
+        assert!(deferral.clear_to_send_with(false, &mut rng));
+    }
+}
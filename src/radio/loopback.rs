@@ -0,0 +1,54 @@
+use anyhow::Context;
+use futures::future::BoxFuture;
+use tokio::sync::mpsc::Sender;
+
+use crate::{db::TxState, framing, tx_queue::TxQueue};
+use super::{report_status, RadioBackend, TxStatus};
+
+/// In-memory backend that receives every frame it transmits. Useful to run the
+/// whole node (UI, database, tunnel) without any hardware or external tool.
+pub struct LoopbackRadio {
+    receive_queue: Sender<(Vec<u8>, f64)>,
+    transmit_queue: TxQueue,
+    status_queue: Sender<TxStatus>,
+}
+
+impl LoopbackRadio {
+    pub fn new(
+        receive_queue: Sender<(Vec<u8>, f64)>,
+        transmit_queue: TxQueue,
+        status_queue: Sender<TxStatus>) -> Self {
+        Self {
+            receive_queue,
+            transmit_queue,
+            status_queue,
+        }
+    }
+}
+
+impl RadioBackend for LoopbackRadio {
+    fn name(&self) -> &'static str {
+        "loopback"
+    }
+
+    fn process_forever(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            loop {
+                let frame = self.transmit_queue.pop().await;
+                // The radio receives the frame without its length prefix
+                let received = match framing::strip_length_prefix(&frame.data) {
+                    Ok(received) => received.to_vec(),
+                    Err(e) => {
+                        report_status(&self.status_queue, frame.id, TxState::Failed, Some(e.to_string())).await;
+                        continue;
+                    },
+                };
+                report_status(&self.status_queue, frame.id, TxState::Sent, None).await;
+                let rssi = 0f64;
+                self.receive_queue.send((received, rssi)).await
+                    .ok()
+                    .context("RX channel died")?;
+            }
+        })
+    }
+}
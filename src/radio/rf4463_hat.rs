@@ -0,0 +1,610 @@
+use anyhow::{anyhow, bail, Context};
+use futures::future::BoxFuture;
+use log::{info, warn};
+use rf4463::{config::RADIO_CONFIG_CATS, Rf4463};
+use rppal::{
+    gpio::{Gpio, InputPin, OutputPin, Trigger},
+    hal::Delay,
+    spi::{Bus, Mode, SlaveSelect, Spi},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{
+    mpsc::Sender,
+    watch,
+    Mutex,
+    Notify,
+};
+use tokio::time::Instant;
+
+use crate::{config, db::TxState, tx_queue::TxQueue};
+use super::{report_result, report_status, Csma, RadioBackend, TxFrame, TxStatus, TxTimeout, MAX_PACKET_LEN};
+
+// Polling period of the radio when no IRQ pin is configured
+const POLL_INTERVAL: Duration = Duration::from_millis(25);
+// In interrupt mode, the radio is still checked this often in case an edge was missed
+const IRQ_TIMEOUT: Duration = Duration::from_secs(1);
+const TEMPERATURE_INTERVAL: Duration = Duration::from_secs(30);
+const SHUTDOWN_TIME: Duration = Duration::from_millis(10);
+
+/// Operations of the RF4463 driver used by the RadioManager, so that
+/// it can run on something else than the real transceiver.
+pub trait RadioDriver: Send {
+    fn set_channel(&mut self, channel: u8) -> anyhow::Result<()>;
+    fn get_temp(&mut self) -> anyhow::Result<f32>;
+    fn is_idle(&mut self) -> anyhow::Result<bool>;
+    fn is_busy_rxing(&mut self) -> anyhow::Result<bool>;
+    fn start_rx(&mut self) -> anyhow::Result<()>;
+    /// Service the radio interrupts, moving data between the FIFO and the buffers
+    fn interrupt(&mut self, rx_buf: Option<&mut [u8]>, tx_data: Option<&[u8]>) -> anyhow::Result<()>;
+    /// Returns the received frame and its RSSI, once it is complete
+    fn finish_rx(&mut self, rx_buf: &mut [u8]) -> anyhow::Result<Option<(Vec<u8>, f64)>>;
+    fn start_tx(&mut self, data: &[u8]) -> anyhow::Result<()>;
+    /// Power cycle and reconfigure the transceiver. The channel must be set again afterwards.
+    fn reset(&mut self) -> BoxFuture<'_, anyhow::Result<()>>;
+}
+
+type Rf4463Spi = Rf4463<Spi, OutputPin, OutputPin, Delay>;
+
+/// The RF4463 wired as described in the `[radio]` section of the configuration
+pub struct Rf4463Hat {
+    conf: config::RadioConfig,
+    // None when the last reset failed
+    radio: Option<Rf4463Spi>,
+}
+
+impl Rf4463Hat {
+    pub fn new(conf: &config::RadioConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            conf: conf.clone(),
+            radio: Some(Self::open(conf)?),
+        })
+    }
+
+    fn open(conf: &config::RadioConfig) -> anyhow::Result<Rf4463Spi> {
+        let spi = Spi::new(spi_bus(conf.spi_bus), spi_slave_select(conf.spi_slave_select), conf.spi_clock_hz, Mode::Mode0)
+            .with_context(|| format!("opening SPI bus {} with slave select {}", conf.spi_bus, conf.spi_slave_select))?;
+        let gpio = Gpio::new().context("opening GPIO")?;
+        let sdn = gpio.get(conf.sdn_pin)
+            .with_context(|| format!("getting SDN pin GPIO{}", conf.sdn_pin))?
+            .into_output();
+        let cs = gpio.get(conf.cs_pin)
+            .with_context(|| format!("getting CS pin GPIO{}", conf.cs_pin))?
+            .into_output();
+
+        let delay = Delay::new();
+
+        Rf4463::new(spi, sdn, cs, delay, &mut RADIO_CONFIG_CATS.clone())
+            .map_err(|e| anyhow!("RF4463 does not respond: {e:?}"))
+    }
+
+    fn radio(&mut self) -> anyhow::Result<&mut Rf4463Spi> {
+        self.radio.as_mut().context("RF4463 is not initialized")
+    }
+}
+
+impl RadioDriver for Rf4463Hat {
+    fn set_channel(&mut self, channel: u8) -> anyhow::Result<()> {
+        self.radio()?.set_channel(channel);
+        Ok(())
+    }
+
+    fn get_temp(&mut self) -> anyhow::Result<f32> {
+        self.radio()?.get_temp().map_err(|e| anyhow!("{e}"))
+    }
+
+    fn is_idle(&mut self) -> anyhow::Result<bool> {
+        Ok(self.radio()?.is_idle())
+    }
+
+    fn is_busy_rxing(&mut self) -> anyhow::Result<bool> {
+        self.radio()?.is_busy_rxing().map_err(|e| anyhow!("{e}"))
+    }
+
+    fn start_rx(&mut self) -> anyhow::Result<()> {
+        self.radio()?.start_rx(None, false).map_err(|e| anyhow!("{e}"))
+    }
+
+    fn interrupt(&mut self, rx_buf: Option<&mut [u8]>, tx_data: Option<&[u8]>) -> anyhow::Result<()> {
+        self.radio()?.interrupt(rx_buf, tx_data).map_err(|e| anyhow!("{e:?}"))
+    }
+
+    fn finish_rx(&mut self, rx_buf: &mut [u8]) -> anyhow::Result<Option<(Vec<u8>, f64)>> {
+        Ok(self.radio()?.finish_rx(rx_buf)
+            .map_err(|e| anyhow!("{e}"))?
+            .map(|data| (data.data().to_vec(), data.rssi())))
+    }
+
+    fn start_tx(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.radio()?.start_tx(data).map_err(|e| anyhow!("{e:?}"))
+    }
+
+    fn reset(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            // Release the SPI device and the pins
+            self.radio = None;
+
+            // Hold the transceiver in shutdown for a moment, the driver powers it up again
+            {
+                let mut sdn = Gpio::new().context("opening GPIO")?
+                    .get(self.conf.sdn_pin)
+                    .with_context(|| format!("getting SDN pin GPIO{}", self.conf.sdn_pin))?
+                    .into_output();
+                sdn.set_high();
+                tokio::time::sleep(SHUTDOWN_TIME).await;
+            }
+
+            self.radio = Some(Self::open(&self.conf)?);
+            Ok(())
+        })
+    }
+}
+
+// The configuration was validated when loading it
+fn spi_bus(bus: u8) -> Bus {
+    match bus {
+        0 => Bus::Spi0,
+        1 => Bus::Spi1,
+        2 => Bus::Spi2,
+        3 => Bus::Spi3,
+        4 => Bus::Spi4,
+        5 => Bus::Spi5,
+        _ => Bus::Spi6,
+    }
+}
+
+fn spi_slave_select(ss: u8) -> SlaveSelect {
+    match ss {
+        0 => SlaveSelect::Ss0,
+        1 => SlaveSelect::Ss1,
+        _ => SlaveSelect::Ss2,
+    }
+}
+
+/// Drives the RF4463. Without IRQ the radio is polled every 25 ms, otherwise
+/// the manager sleeps until the radio asserts nIRQ or a frame is queued.
+pub struct RadioManager<D: RadioDriver = Rf4463Hat> {
+    radio: D,
+    // Notified on every falling edge of nIRQ, in interrupt mode
+    irq: Option<Arc<Notify>>,
+    // Keeps the interrupt registered
+    _irq_pin: Option<InputPin>,
+
+    receive_queue: Sender<(Vec<u8>, f64)>,
+    transmit_queue: TxQueue,
+    status_queue: Sender<TxStatus>,
+    frequency: watch::Receiver<u32>,
+    csma: Csma,
+    rx_buf: [u8; MAX_PACKET_LEN],
+    temperature: Arc<Mutex<f32>>,
+    last_temperature: Instant,
+}
+
+impl RadioManager {
+    pub fn new(
+        conf: &config::RadioConfig,
+        receive_queue: Sender<(Vec<u8>, f64)>,
+        transmit_queue: TxQueue,
+        status_queue: Sender<TxStatus>,
+        frequency: watch::Receiver<u32>,
+        csma: Csma) -> anyhow::Result<Self> {
+        let radio = Rf4463Hat::new(conf)?;
+
+        let (irq, irq_pin) = match conf.irq_pin {
+            Some(pin) => {
+                let mut irq_pin = Gpio::new().context("opening GPIO")?
+                    .get(pin)
+                    .with_context(|| format!("getting IRQ pin GPIO{pin}"))?
+                    .into_input_pullup();
+
+                // nIRQ is active low
+                let irq = Arc::new(Notify::new());
+                let irq_callback = irq.clone();
+                irq_pin.set_async_interrupt(Trigger::FallingEdge, move |_| irq_callback.notify_one())
+                    .with_context(|| format!("setting up interrupt on GPIO{pin}"))?;
+
+                info!("Radio in interrupt mode, IRQ on GPIO{pin}");
+                (Some(irq), Some(irq_pin))
+            },
+            None => (None, None),
+        };
+
+        let mut manager = Self::with_driver(radio, irq, receive_queue, transmit_queue, status_queue, frequency, csma)?;
+        manager._irq_pin = irq_pin;
+        Ok(manager)
+    }
+}
+
+impl<D: RadioDriver> RadioManager<D> {
+    /// Without `irq` the radio is polled
+    pub fn with_driver(
+        mut radio: D,
+        irq: Option<Arc<Notify>>,
+        receive_queue: Sender<(Vec<u8>, f64)>,
+        transmit_queue: TxQueue,
+        status_queue: Sender<TxStatus>,
+        mut frequency: watch::Receiver<u32>,
+        csma: Csma) -> anyhow::Result<Self> {
+        let channel = config::channel_for_frequency(*frequency.borrow_and_update())?;
+        radio.set_channel(channel)?;
+
+        let rx_buf = [0; MAX_PACKET_LEN];
+        let temperature = Arc::new(Mutex::new(radio.get_temp()?));
+
+        Ok(Self {
+            radio,
+            irq,
+            _irq_pin: None,
+            receive_queue,
+            transmit_queue,
+            status_queue,
+            frequency,
+            csma,
+            rx_buf,
+            temperature,
+            last_temperature: Instant::now(),
+        })
+    }
+
+    // Apply the frequency published since the last call, if any
+    fn follow_frequency(&mut self) {
+        if !self.frequency.has_changed().unwrap_or(false) {
+            return;
+        }
+
+        let freq = *self.frequency.borrow_and_update();
+        match config::channel_for_frequency(freq) {
+            Ok(channel) => {
+                if let Err(e) = self.radio.set_channel(channel) {
+                    warn!("Could not retune radio: {e}");
+                    return;
+                }
+                info!("Radio retuned to {freq} kHz");
+            },
+            Err(e) => warn!("Not retuning radio: {e}"),
+        }
+    }
+
+    async fn read_temperature(&mut self) -> anyhow::Result<()> {
+        *self.temperature.lock().await = self.radio.get_temp()?;
+        self.last_temperature = Instant::now();
+        Ok(())
+    }
+
+    // Wait until nIRQ is asserted in interrupt mode, or for one polling period
+    async fn wait_for_radio(&mut self) {
+        match &self.irq {
+            Some(irq) => {
+                // A timeout is no error, the radio is checked anyway
+                let _ = tokio::time::timeout(IRQ_TIMEOUT, irq.notified()).await;
+            },
+            None => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+
+    async fn tick(&mut self) -> anyhow::Result<()> {
+        if self.radio.is_idle()? {
+            self.radio.start_rx()?;
+
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+
+        self.radio.interrupt(Some(&mut self.rx_buf), None)?;
+
+        if let Some((data, rssi)) = self.radio.finish_rx(&mut self.rx_buf)? {
+            self.radio.start_rx()?;
+
+            self.receive_queue
+                .send((data, rssi))
+                .await
+                .ok()
+                .context("RX channel died")?;
+        }
+
+        Ok(())
+    }
+
+    async fn tx(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        // ensures we don't tx over a packet
+        self.channel_access().await?;
+
+        self.radio.start_tx(data)?;
+
+        const TIMEOUT: Duration = Duration::from_secs(10);
+        let start_time = Instant::now();
+        while !self.radio.is_idle()? {
+            self.radio.interrupt(None, Some(data))?;
+
+            if start_time + TIMEOUT < Instant::now() {
+                bail!(TxTimeout);
+            }
+
+            self.wait_for_radio().await;
+        }
+
+        Ok(())
+    }
+
+    async fn channel_access(&mut self) -> anyhow::Result<()> {
+        let mut deferral = self.csma.deferral();
+        loop {
+            let busy = self.radio.is_busy_rxing()?;
+            if deferral.clear_to_send(busy) {
+                break Ok(());
+            }
+
+            if busy {
+                self.tick().await?;
+            }
+
+            // slots are shorter than 100 ms, so we can safely sleep without calling tick
+            tokio::time::sleep(self.csma.slot_time()).await;
+        }
+    }
+
+    async fn transmit(&mut self, frame: TxFrame) -> anyhow::Result<()> {
+        report_status(&self.status_queue, frame.id, TxState::Transmitting, None).await;
+        let result = self.tx(&frame.data).await;
+        report_result(&self.status_queue, frame.id, &result).await;
+        result
+    }
+
+    // Wait for the radio, and return the next frame to transmit if there is one
+    async fn next_frame(&mut self) -> Option<TxFrame> {
+        match self.irq.clone() {
+            Some(irq) => {
+                tokio::select! {
+                    _ = tokio::time::timeout(IRQ_TIMEOUT, irq.notified()) => None,
+                    frame = self.transmit_queue.pop() => Some(frame),
+                }
+            },
+            None => {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                self.transmit_queue.try_pop()
+            },
+        }
+    }
+}
+
+impl<D: RadioDriver> RadioBackend for RadioManager<D> {
+    fn name(&self) -> &'static str {
+        "rf4463"
+    }
+
+    fn temperature_mutex(&self) -> Option<Arc<Mutex<f32>>> {
+        Some(self.temperature.clone())
+    }
+
+    fn reset(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.radio.reset().await?;
+
+            let channel = config::channel_for_frequency(*self.frequency.borrow_and_update())?;
+            self.radio.set_channel(channel)?;
+            self.read_temperature().await
+        })
+    }
+
+    fn process_forever(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            loop {
+                self.follow_frequency();
+
+                self.tick().await?;
+
+                if self.last_temperature.elapsed() >= TEMPERATURE_INTERVAL {
+                    self.read_temperature().await?;
+                }
+
+                if let Some(frame) = self.next_frame().await {
+                    self.transmit(frame).await?;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use tokio::sync::mpsc;
+    use crate::db::TxSource;
+
+    #[derive(PartialEq)]
+    enum Mode {
+        Idle,
+        Rx,
+        Tx,
+    }
+
+    struct MockState {
+        mode: Mode,
+        channel: Option<u8>,
+        num_interrupts: u32,
+        num_temperature_reads: u32,
+        // Frames the radio receives once it is in RX
+        incoming: VecDeque<(Vec<u8>, f64)>,
+        transmitted: Vec<Vec<u8>>,
+    }
+
+    // Transceiver that finishes a transmission on the first interrupt after it started
+    #[derive(Clone)]
+    struct MockDriver(Arc<std::sync::Mutex<MockState>>);
+
+    impl MockDriver {
+        fn new() -> Self {
+            Self(Arc::new(std::sync::Mutex::new(MockState {
+                mode: Mode::Idle,
+                channel: None,
+                num_interrupts: 0,
+                num_temperature_reads: 0,
+                incoming: VecDeque::new(),
+                transmitted: Vec::new(),
+            })))
+        }
+
+        fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+            self.0.lock().unwrap()
+        }
+    }
+
+    impl RadioDriver for MockDriver {
+        fn set_channel(&mut self, channel: u8) -> anyhow::Result<()> {
+            self.state().channel = Some(channel);
+            Ok(())
+        }
+
+        fn get_temp(&mut self) -> anyhow::Result<f32> {
+            let mut state = self.state();
+            state.num_temperature_reads += 1;
+            Ok(20.0 + state.num_temperature_reads as f32)
+        }
+
+        fn is_idle(&mut self) -> anyhow::Result<bool> {
+            Ok(self.state().mode == Mode::Idle)
+        }
+
+        fn is_busy_rxing(&mut self) -> anyhow::Result<bool> {
+            Ok(false)
+        }
+
+        fn start_rx(&mut self) -> anyhow::Result<()> {
+            self.state().mode = Mode::Rx;
+            Ok(())
+        }
+
+        fn interrupt(&mut self, _rx_buf: Option<&mut [u8]>, tx_data: Option<&[u8]>) -> anyhow::Result<()> {
+            let mut state = self.state();
+            state.num_interrupts += 1;
+            if state.mode == Mode::Tx && tx_data.is_some() {
+                state.mode = Mode::Idle;
+            }
+            Ok(())
+        }
+
+        fn finish_rx(&mut self, _rx_buf: &mut [u8]) -> anyhow::Result<Option<(Vec<u8>, f64)>> {
+            let mut state = self.state();
+            if state.mode != Mode::Rx {
+                return Ok(None);
+            }
+            Ok(state.incoming.pop_front())
+        }
+
+        fn start_tx(&mut self, data: &[u8]) -> anyhow::Result<()> {
+            let mut state = self.state();
+            state.transmitted.push(data.to_vec());
+            state.mode = Mode::Tx;
+            Ok(())
+        }
+
+        fn reset(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    struct Harness {
+        driver: MockDriver,
+        irq: Arc<Notify>,
+        temperature: Arc<Mutex<f32>>,
+        receive: mpsc::Receiver<(Vec<u8>, f64)>,
+        status: mpsc::Receiver<TxStatus>,
+        transmit_queue: TxQueue,
+        frequency: watch::Sender<u32>,
+    }
+
+    // Run a manager on 430.5 MHz, in interrupt mode or polling the radio
+    fn start(interrupt_mode: bool) -> Harness {
+        let driver = MockDriver::new();
+        let irq = Arc::new(Notify::new());
+        let (receive_queue, receive) = mpsc::channel(16);
+        let (status_queue, status) = mpsc::channel(16);
+        let transmit_queue = TxQueue::default();
+        let (frequency, frequency_receive) = watch::channel(430500);
+        let csma = Csma::new(&config::CsmaConfig::default());
+
+        let mut manager = RadioManager::with_driver(
+            driver.clone(),
+            interrupt_mode.then(|| irq.clone()),
+            receive_queue,
+            transmit_queue.clone(),
+            status_queue,
+            frequency_receive,
+            csma).unwrap();
+        let temperature = manager.temperature_mutex().unwrap();
+        tokio::task::spawn(async move { manager.process_forever().await });
+
+        Harness { driver, irq, temperature, receive, status, transmit_queue, frequency }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn polls_radio_without_irq() {
+        let h = start(false);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let num_interrupts = h.driver.state().num_interrupts;
+        assert!((38..=41).contains(&num_interrupts), "{num_interrupts} interrupts in 1 s");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_irq() {
+        let h = start(true);
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+
+        // The first tick, then one per IRQ timeout
+        assert_eq!(h.driver.state().num_interrupts, 3);
+
+        h.irq.notify_one();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(h.driver.state().num_interrupts, 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_temperature_periodically() {
+        let h = start(true);
+        assert_eq!(*h.temperature.lock().await, 21.0);
+
+        tokio::time::sleep(Duration::from_secs(65)).await;
+        assert_eq!(h.driver.state().num_temperature_reads, 3);
+        assert_eq!(*h.temperature.lock().await, 23.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retunes_when_frequency_changes() {
+        let h = start(false);
+        assert_eq!(h.driver.state().channel, Some(20));
+
+        h.frequency.send(430525).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(h.driver.state().channel, Some(21));
+
+        // Off the channel raster, the radio stays where it is
+        h.frequency.send(430510).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(h.driver.state().channel, Some(21));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transmits_queued_frames() {
+        let mut h = start(true);
+        h.transmit_queue.push(7, TxSource::Ui, vec![1, 2, 3], None).unwrap();
+
+        for expected in [TxState::Transmitting, TxState::Sent] {
+            let status = tokio::time::timeout(Duration::from_secs(10), h.status.recv()).await
+                .unwrap()
+                .unwrap();
+            assert_eq!((status.id, status.state), (7, expected));
+        }
+        assert_eq!(h.driver.state().transmitted, vec![vec![1, 2, 3]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forwards_received_frames() {
+        let mut h = start(false);
+        h.driver.state().incoming.push_back((vec![4, 5, 6], -90.0));
+
+        let received = tokio::time::timeout(Duration::from_secs(1), h.receive.recv()).await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, (vec![4, 5, 6], -90.0));
+    }
+}
@@ -0,0 +1,321 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Instant;
+use anyhow::Context;
+use futures::future::BoxFuture;
+use log::{debug, info, warn};
+use rand::{thread_rng, Rng};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+
+use crate::{airtime, config, db::TxState, framing, tx_queue::TxQueue};
+use super::{report_result, report_status, Csma, RadioBackend, TxStatus};
+
+/// Behaves like the radio, but uses UDP instead of the RF channel.
+/// By default frames are received on 127.0.0.1:9073 and transmitted to 127.0.0.1:9074,
+/// which is what the fake-radio tool expects.
+/// If the RX address is a multicast group, the node joins it and shares the port
+/// with the other nodes of the host. Nodes sending to the same group then share
+/// one virtual channel.
+/// Changing the frequency rebinds the socket, dropping the frames not received yet.
+///
+/// Datagrams are framed as described in the framing module. fake-radio adds the
+/// RSSI when simulating a channel.
+///
+/// The channel is sensed busy during the airtime of every received frame, and
+/// in a configurable share of the slots, to exercise channel access.
+pub struct UdpRadio {
+    socket: UdpSocket,
+    // Frames are sent from their own socket, to recognise them when
+    // they come back through the multicast group
+    tx_socket: UdpSocket,
+    rx_address: SocketAddr,
+    tx_address: SocketAddr,
+    receive_queue: Sender<(Vec<u8>, f64)>,
+    transmit_queue: TxQueue,
+    status_queue: Sender<TxStatus>,
+    frequency: watch::Receiver<u32>,
+    csma: Csma,
+    simulated_busy_percent: u8,
+    busy_until: Instant,
+}
+
+impl UdpRadio {
+    pub async fn new(
+        conf: &config::RadioConfig,
+        receive_queue: Sender<(Vec<u8>, f64)>,
+        transmit_queue: TxQueue,
+        status_queue: Sender<TxStatus>,
+        frequency: watch::Receiver<u32>,
+        csma: Csma) -> anyhow::Result<Self> {
+        let rx_address : SocketAddr = conf.udp_rx_address.parse()
+            .with_context(|| format!("invalid UDP RX address {}", conf.udp_rx_address))?;
+        let tx_address : SocketAddr = conf.udp_tx_address.parse()
+            .with_context(|| format!("invalid UDP TX address {}", conf.udp_tx_address))?;
+
+        let socket = bind_rx(rx_address).await?;
+        let tx_socket = bind_tx(tx_address).await?;
+
+        Ok(Self {
+            socket,
+            tx_socket,
+            rx_address,
+            tx_address,
+            receive_queue,
+            transmit_queue,
+            status_queue,
+            frequency,
+            csma,
+            simulated_busy_percent: conf.simulated_busy_percent,
+            busy_until: Instant::now(),
+        })
+    }
+
+    async fn rebind(&mut self) -> anyhow::Result<()> {
+        // Release the port before binding it again
+        self.socket = UdpSocket::bind("127.0.0.1:0").await
+            .context("binding temporary fake radio socket")?;
+        self.socket = bind_rx(self.rx_address).await?;
+        Ok(())
+    }
+
+    fn channel_busy(&self) -> bool {
+        Instant::now() < self.busy_until
+            || thread_rng().gen_range(0..100) < self.simulated_busy_percent
+    }
+
+    async fn handle_datagram(&mut self, data: &[u8], addr: SocketAddr) -> anyhow::Result<()> {
+        debug!("{:?} bytes received from {:?}", data.len(), addr);
+        if self.rx_address.ip().is_multicast() && addr == self.tx_socket.local_addr()? {
+            // Our own frame, looped back by the group
+            return Ok(());
+        }
+
+        let (frame, rssi) = match framing::decode_datagram(data) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Invalid datagram from {addr}: {e}");
+                return Ok(());
+            },
+        };
+
+        // The frame occupies the channel for its airtime
+        self.busy_until = Instant::now() + airtime::airtime(framing::PREFIX_LEN + frame.len());
+
+        self.receive_queue.send((frame.to_vec(), rssi.unwrap_or(0.0))).await
+            .ok()
+            .context("RX channel died")
+    }
+
+    // Wait for the channel, receiving the frames heard meanwhile
+    async fn channel_access(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        let mut deferral = self.csma.deferral();
+        while !deferral.clear_to_send(self.channel_busy()) {
+            tokio::select! {
+                _ = tokio::time::sleep(self.csma.slot_time()) => (),
+                received = self.socket.recv_from(buf) => {
+                    let (len, addr) = received.context("receiving from fake radio socket")?;
+                    self.handle_datagram(&buf[..len], addr).await?;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+fn unspecified(address: SocketAddr, port: u16) -> SocketAddr {
+    match address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, port).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, port).into(),
+    }
+}
+
+/* Bind the socket frames are sent from to the address of the interface
+ * towards the destination, which receivers see as the source of the frames.
+ * Other hosts may use the same port. */
+async fn bind_tx(destination: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let source = || -> std::io::Result<IpAddr> {
+        let probe = std::net::UdpSocket::bind(unspecified(destination, 0))?;
+        probe.connect(destination)?;
+        Ok(probe.local_addr()?.ip())
+    };
+
+    let source = source().with_context(|| format!("finding the interface towards {destination}"))?;
+    UdpSocket::bind((source, 0)).await
+        .with_context(|| format!("binding fake radio TX socket to {source}"))
+}
+
+// Bind the socket frames are received on, joining the group of a multicast address
+async fn bind_rx(address: SocketAddr) -> anyhow::Result<UdpSocket> {
+    if !address.ip().is_multicast() {
+        return UdpSocket::bind(address).await
+            .with_context(|| format!("binding fake radio socket to {address}"));
+    }
+
+    let join = || -> std::io::Result<socket2::Socket> {
+        let socket = socket2::Socket::new(socket2::Domain::for_address(address), socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+        // Other nodes of the host listen to the same group and port
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&unspecified(address, address.port()).into())?;
+        match address.ip() {
+            IpAddr::V4(group) => socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?,
+            IpAddr::V6(group) => socket.join_multicast_v6(&group, 0)?,
+        }
+        Ok(socket)
+    };
+
+    let socket = join().with_context(|| format!("joining multicast group {address}"))?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+impl RadioBackend for UdpRadio {
+    fn name(&self) -> &'static str {
+        "udp"
+    }
+
+    fn reset(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(self.rebind())
+    }
+
+    fn process_forever(&mut self) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            let mut buf = [0; framing::MAX_DATAGRAM_LEN];
+            loop {
+                tokio::select! {
+                    received = self.socket.recv_from(&mut buf) => {
+                        let (len, addr) = received.context("receiving from fake radio socket")?;
+                        self.handle_datagram(&buf[..len], addr).await?;
+                    },
+                    frame = self.transmit_queue.pop() => {
+                        report_status(&self.status_queue, frame.id, TxState::Transmitting, None).await;
+                        let result = match self.channel_access(&mut buf).await {
+                            Ok(()) => self.tx_socket.send_to(&framing::encode_datagram(&frame.data, None), self.tx_address).await
+                                .map(|_| ())
+                                .context("sending to fake radio socket"),
+                            Err(e) => Err(e),
+                        };
+                        report_result(&self.status_queue, frame.id, &result).await;
+                        result?;
+                    },
+                    changed = self.frequency.changed() => {
+                        changed.context("frequency channel closed")?;
+                        let freq = *self.frequency.borrow_and_update();
+                        self.rebind().await?;
+                        info!("Fake radio retuned to {freq} kHz");
+                    },
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use crate::db::TxSource;
+
+    fn radio_config(rx_address: &str, tx_address: &str) -> config::RadioConfig {
+        config::RadioConfig {
+            backend: config::RadioBackendKind::Udp,
+            udp_rx_address: rx_address.to_owned(),
+            udp_tx_address: tx_address.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    // Send the frames from one backend to the other, returning what the receiving backend delivers
+    async fn send_between_nodes(frames: &[Vec<u8>], simulated_busy_percent: u8, csma: &Csma) -> Vec<(Vec<u8>, f64)> {
+        let (receive_queue, mut receive) = mpsc::channel(16);
+        let (status_queue, _status) = mpsc::channel(16);
+        let (_frequency, frequency_receive) = watch::channel(430500);
+        let mut receiver = UdpRadio::new(&radio_config("127.0.0.1:0", "127.0.0.1:9"),
+            receive_queue, TxQueue::default(), status_queue.clone(), frequency_receive.clone(), Csma::new(&config::CsmaConfig::default())).await.unwrap();
+        let receiver_address = receiver.socket.local_addr().unwrap().to_string();
+
+        let (unused_queue, _unused) = mpsc::channel(16);
+        let transmit_queue = TxQueue::default();
+        let sender_conf = config::RadioConfig {
+            simulated_busy_percent,
+            ..radio_config("127.0.0.1:0", &receiver_address)
+        };
+        let mut sender = UdpRadio::new(&sender_conf,
+            unused_queue, transmit_queue.clone(), status_queue, frequency_receive, csma.clone()).await.unwrap();
+
+        tokio::spawn(async move { receiver.process_forever().await });
+        tokio::spawn(async move { sender.process_forever().await });
+
+        let mut received = Vec::new();
+        for (id, frame) in frames.iter().enumerate() {
+            transmit_queue.push(id as i64, TxSource::Ui, frame.clone(), None).unwrap();
+            let frame = tokio::time::timeout(Duration::from_secs(5), receive.recv()).await
+                .expect("frame not received")
+                .unwrap();
+            received.push(frame);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn receives_frames_over_a_kilobyte() {
+        let frames = [vec![0x5A; 1500], vec![0xA5; crate::radio::MAX_PACKET_LEN]];
+        let sent : Vec<Vec<u8>> = frames.iter().map(|f| framing::add_length_prefix(f).unwrap()).collect();
+
+        let received = send_between_nodes(&sent, 0, &Csma::new(&config::CsmaConfig::default())).await;
+        for (frame, (data, rssi)) in frames.iter().zip(received) {
+            assert_eq!(&data, frame);
+            assert_eq!(rssi, 0.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn defers_frames_while_simulated_busy() {
+        let csma = Csma::new(&config::CsmaConfig {
+            slot_time_ms: 5,
+            persistence: 255,
+            max_deferral_ms: 50,
+        });
+        let frame = vec![0x5A; 10];
+
+        let started = std::time::Instant::now();
+        let received = send_between_nodes(&[framing::add_length_prefix(&frame).unwrap()], 100, &csma).await;
+        assert_eq!(received[0].0, frame);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // Sent anyway once the maximum deferral is over
+        let stats = csma.stats();
+        assert_eq!((stats.num_frames, stats.num_busy, stats.num_forced), (1, 1, 1));
+        assert!(stats.num_deferred_slots > 0);
+    }
+
+    #[tokio::test]
+    async fn ignores_only_own_frames_from_multicast_group() {
+        let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let group = format!("239.255.73.{}:{port}", port % 200 + 1);
+
+        let (receive_queue, mut receive) = mpsc::channel(16);
+        let (status_queue, mut status) = mpsc::channel(16);
+        let (_frequency, frequency_receive) = watch::channel(430500);
+        let transmit_queue = TxQueue::default();
+        let mut radio = UdpRadio::new(&radio_config(&group, &group),
+            receive_queue, transmit_queue.clone(), status_queue, frequency_receive, Csma::new(&config::CsmaConfig::default())).await.unwrap();
+        let own_address = radio.tx_socket.local_addr().unwrap();
+        tokio::spawn(async move { radio.process_forever().await });
+
+        // Our frame comes back through the group
+        transmit_queue.push(1, TxSource::Ui, framing::add_length_prefix(&[1, 2, 3]).unwrap(), None).unwrap();
+        while status.recv().await.unwrap().state != TxState::Sent {}
+        assert!(tokio::time::timeout(Duration::from_millis(200), receive.recv()).await.is_err());
+
+        // Another host sending from the same port
+        let other = UdpSocket::bind(("127.0.0.1", own_address.port())).await.unwrap();
+        assert_ne!(other.local_addr().unwrap(), own_address);
+        other.send_to(&framing::add_length_prefix(&[4, 5, 6]).unwrap(), ("127.0.0.1", port)).await.unwrap();
+        let (frame, _) = tokio::time::timeout(Duration::from_secs(5), receive.recv()).await
+            .expect("frame from other host not received")
+            .unwrap();
+        assert_eq!(frame, [4, 5, 6]);
+    }
+}
@@ -0,0 +1,174 @@
+use std::time::{Duration, Instant};
+use anyhow::Context;
+use log::{debug, warn};
+use tokio::sync::mpsc;
+
+use crate::airtime;
+use crate::framing;
+use crate::db::{self, TxSource, TxState};
+use crate::radio::TxStatus;
+use crate::ui;
+use crate::SharedState;
+
+const EXPIRY_INTERVAL : Duration = Duration::from_secs(5);
+
+// The frame will not be transmitted, its airtime is available again
+fn release_airtime(shared_state: &SharedState, id: i64) {
+    shared_state.lock().unwrap().airtime.release(id);
+}
+
+async fn mark(db: &mut db::Database, id: i64, state: TxState, reason: &str) {
+    if let Err(e) = db.update_transmission(id, state, Some(reason), None).await {
+        warn!("Failed to update transmission {id}: {e}");
+    }
+}
+
+/* Record the frame in the transmit log and add it to the transmit queue.
+ * The frame must be fully encoded, including the length prefix.
+ * Frames over the airtime limits are held back in the queue, or dropped.
+ * Returns the id of the transmission. */
+pub async fn queue(shared_state: &SharedState, data: Vec<u8>, source: TxSource) -> anyhow::Result<i64> {
+    let (transmit_queue, mut db) = {
+        let s = shared_state.lock().unwrap();
+        (s.transmit_queue.clone(), s.db.clone())
+    };
+
+    let frame = framing::strip_length_prefix(&data)?;
+    let id = db.queue_transmission(frame, source).await
+        .context("Failed to write transmission to sqlite")?;
+
+    let decision = {
+        let mut s = shared_state.lock().unwrap();
+        let conf = s.conf.borrow().airtime.clone();
+        s.airtime.request(&conf, id, source, airtime::airtime(data.len()))
+    };
+
+    let hold_until = match decision {
+        airtime::Decision::Allow => None,
+        airtime::Decision::Delay(wait) => {
+            debug!("Holding transmission {id} for {}s to respect the airtime limits", wait.as_secs());
+            Some(Instant::now() + wait)
+        },
+        airtime::Decision::Deny => {
+            mark(&mut db, id, TxState::Failed, "Airtime limit exceeded").await;
+            anyhow::bail!("Airtime limit exceeded");
+        },
+    };
+
+    match transmit_queue.push(id, source, data, hold_until) {
+        Ok(Some(evicted)) => {
+            debug!("Transmission {evicted} dropped to make room for {id}");
+            release_airtime(shared_state, evicted);
+            mark(&mut db, evicted, TxState::Failed, "Dropped for a frame of higher priority").await;
+        },
+        Ok(None) => (),
+        Err(e) => {
+            release_airtime(shared_state, id);
+            mark(&mut db, id, TxState::Failed, &e.to_string()).await;
+            return Err(e);
+        },
+    }
+
+    debug!("Queued transmission {id} from {}", source.as_str());
+    Ok(id)
+}
+
+// Remove a frame from the transmit queue, if it was not sent yet
+pub async fn cancel(shared_state: &SharedState, id: i64) -> bool {
+    let (transmit_queue, mut db) = {
+        let s = shared_state.lock().unwrap();
+        (s.transmit_queue.clone(), s.db.clone())
+    };
+
+    if !transmit_queue.cancel(id) {
+        return false;
+    }
+
+    debug!("Transmission {id} cancelled");
+    release_airtime(shared_state, id);
+    mark(&mut db, id, TxState::Cancelled, "Cancelled from the UI").await;
+    true
+}
+
+/* Keep the transmit log up to date with the state reported by the radio.
+ * Sent frames are also stored with the other frames, and shown in the chat. */
+pub async fn run(shared_state: SharedState, mut status_receive: mpsc::Receiver<TxStatus>) {
+    let mut expiry = tokio::time::interval(EXPIRY_INTERVAL);
+
+    loop {
+        let status = tokio::select! {
+            status = status_receive.recv() => match status {
+                Some(status) => status,
+                None => break,
+            },
+            _ = expiry.tick() => {
+                let (transmit_queue, mut db) = {
+                    let s = shared_state.lock().unwrap();
+                    (s.transmit_queue.clone(), s.db.clone())
+                };
+                for id in transmit_queue.take_expired() {
+                    debug!("Transmission {id} expired");
+                    release_airtime(&shared_state, id);
+                    mark(&mut db, id, TxState::Expired, "Waited too long in the transmit queue").await;
+                }
+                continue;
+            },
+        };
+
+        let (mut db, ws_broadcast, capture, frequency) = {
+            let s = shared_state.lock().unwrap();
+            (s.db.clone(), s.ws_broadcast.clone(), s.capture.clone(), s.frequency)
+        };
+
+        debug!("Transmission {} is now {}", status.id, status.state.as_str());
+
+        match status.state {
+            TxState::Sent => shared_state.lock().unwrap().radio.num_transmitted += 1,
+            TxState::Failed | TxState::TimedOut => {
+                let mut s = shared_state.lock().unwrap();
+                s.radio.num_tx_errors += 1;
+                s.radio.last_error = status.error.clone();
+                // A frame that timed out may have been on the air
+                if status.state == TxState::Failed {
+                    s.airtime.release(status.id);
+                }
+            },
+            TxState::Queued | TxState::Transmitting | TxState::Cancelled | TxState::Expired => (),
+        }
+
+        let mut frame_id = None;
+        if status.state == TxState::Sent {
+            match db.get_transmission(status.id).await {
+                Ok(Some(transmission)) => {
+                    let metadata = db::FrameMetadata::transmitted(frequency);
+                    match db.store_packet(&transmission.content, &metadata).await {
+                        Ok(id) => {
+                            frame_id = Some(id);
+
+                            let stored = db::Packet::new(id, transmission.content, &metadata);
+                            if let Some(m) = ui::UIPacket::from_db_packet(&stored) {
+                                match ws_broadcast.send(m) {
+                                    Ok(num) => debug!("Send own WS message to {num}"),
+                                    Err(_) => debug!("No WS receivers currently"),
+                                }
+                            }
+
+                            if capture.try_send(stored).is_err() {
+                                warn!("Capture queue full, dropping frame");
+                            }
+                        },
+                        Err(e) => warn!("Failed to write sent frame to sqlite: {}", e),
+                    }
+                },
+                Ok(None) => warn!("Unknown transmission {}", status.id),
+                Err(e) => warn!("Failed to get transmission {}: {}", status.id, e),
+            }
+        }
+
+        if let Err(e) = db.update_transmission(status.id, status.state, status.error.as_deref(), frame_id).await {
+            warn!("Failed to update transmission {}: {}", status.id, e);
+        }
+    }
+
+    warn!("TX status task stopping");
+}
@@ -0,0 +1,127 @@
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use tokio::sync::{mpsc, watch};
+
+use crate::{config, db, framing, radio::MAX_PACKET_LEN, transmit, SharedState};
+
+/* 8191 max packet size would give nearly 32 packets of size 255.
+ * Let's leave some space for other whiskers too. */
+const TUN_MTU : usize = 24*255;
+
+const RETRY_INTERVAL : Duration = Duration::from_secs(10);
+
+fn build_tun_packet(config: &config::Config, ip_packet: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut buf = [0; MAX_PACKET_LEN];
+    let mut pkt = ham_cats::packet::Packet::new(&mut buf);
+    pkt.add_identification(
+        ham_cats::whisker::Identification::new(&config.callsign, config.ssid, config.icon)
+        .context("Invalid identification")?
+        ).map_err(|e| anyhow!("Could not add identification to packet: {e}"))?;
+
+    for part in ip_packet.chunks(255) {
+        pkt.add_arbitrary(ham_cats::whisker::Arbitrary::new(part).unwrap())
+            .map_err(|e| anyhow!("Could not add data to packet: {e}"))?;
+    }
+
+    framing::encode_packet(pkt)
+}
+
+// Runs the TUN device until an error occurs or the tunnel configuration changes
+async fn session(
+    shared_state: &SharedState,
+    conf: &config::TunnelConfig,
+    conf_receive: &mut watch::Receiver<config::Config>,
+    incoming: &mut mpsc::Receiver<Vec<u8>>) -> anyhow::Result<()> {
+    let mut tunconfig = tun::Configuration::default();
+
+    tunconfig
+        .address(conf.local_ip.as_str())
+        .netmask(conf.netmask.as_str())
+        .mtu(TUN_MTU.try_into().unwrap())
+        .up();
+
+    #[cfg(target_os = "linux")]
+    tunconfig.platform(|tunconfig| {
+        tunconfig.packet_information(true);
+    });
+
+    let dev = tun::create_as_async(&tunconfig).context("Could not create TUN device")?;
+    let (mut sink, mut source) = dev.into_framed().split();
+    info!("Tunnel up with address {}", conf.local_ip);
+
+    loop {
+        tokio::select! {
+            packet_from_tun = source.next() => {
+                let ip_packet = packet_from_tun.context("TUN device closed")??;
+                if ip_packet.get_bytes().len() > TUN_MTU {
+                    warn!("TUN: too large packet: {} bytes", ip_packet.get_bytes().len());
+                    continue;
+                }
+
+                debug!("TUN: {} bytes", ip_packet.get_bytes().len());
+
+                // Read the configuration for every packet, the identification may have changed
+                let config = conf_receive.borrow().clone();
+                match build_tun_packet(&config, ip_packet.get_bytes()) {
+                    Ok(data) => if let Err(e) = transmit::queue(shared_state, data, db::TxSource::Tunnel).await {
+                        warn!("Failed to send TUN packet: {e}");
+                    },
+                    Err(e) => warn!("Failed to prepare TUN packet: {e}"),
+                }
+            },
+            data = incoming.recv() => {
+                let data = data.context("Tunnel queue closed")?;
+                if let Err(e) = sink.send(tun::TunPacket::new(data)).await {
+                    warn!("Failed to send to TUN: {}", e);
+                }
+            },
+            changed = conf_receive.changed() => {
+                changed.context("Configuration channel closed")?;
+                if conf_receive.borrow().tunnel != *conf {
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
+
+/* Carry IP packets from the TUN device in Arbitrary whiskers, and write the
+ * content of the Arbitrary whiskers received on the incoming queue to the device.
+ * The device is created, moved or removed as the tunnel configuration changes. */
+pub async fn run(shared_state: SharedState, mut incoming: mpsc::Receiver<Vec<u8>>) {
+    let mut conf_receive = shared_state.lock().unwrap().conf.subscribe();
+
+    loop {
+        let conf = conf_receive.borrow_and_update().tunnel.clone();
+
+        if !conf.enabled {
+            // Discard incoming data while disabled
+            tokio::select! {
+                data = incoming.recv() => if data.is_none() {
+                    break;
+                },
+                changed = conf_receive.changed() => if changed.is_err() {
+                    break;
+                },
+            }
+            continue;
+        }
+
+        match session(&shared_state, &conf, &mut conf_receive, &mut incoming).await {
+            Ok(()) => info!("Tunnel configuration changed"),
+            Err(e) => {
+                warn!("Tunnel error: {e:#}");
+                tokio::select! {
+                    _ = tokio::time::sleep(RETRY_INTERVAL) => (),
+                    changed = conf_receive.changed() => if changed.is_err() {
+                        break;
+                    },
+                }
+            },
+        }
+    }
+
+    warn!("Tunnel task stopping");
+}
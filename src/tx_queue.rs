@@ -0,0 +1,304 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::db::TxSource;
+use crate::radio::TxFrame;
+
+const CAPACITY : usize = 64;
+
+// Lower values are transmitted first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    // Messages from the UI and KISS clients
+    User,
+    Beacon,
+    // Digipeated frames and frames from FELINET
+    Relay,
+    Tunnel,
+}
+
+impl Priority {
+    pub fn of(source: TxSource) -> Self {
+        match source {
+            TxSource::Ui | TxSource::Kiss => Priority::User,
+            TxSource::Beacon => Priority::Beacon,
+            TxSource::Digipeater | TxSource::Felinet => Priority::Relay,
+            TxSource::Tunnel => Priority::Tunnel,
+        }
+    }
+
+    // Frames still queued after this time are not worth sending anymore
+    fn max_age(&self) -> Duration {
+        match self {
+            Priority::User => Duration::from_secs(600),
+            Priority::Beacon => Duration::from_secs(300),
+            Priority::Relay => Duration::from_secs(60),
+            Priority::Tunnel => Duration::from_secs(30),
+        }
+    }
+}
+
+struct Entry {
+    id : i64,
+    source : TxSource,
+    priority : Priority,
+    queued_at : Instant,
+    queued_at_utc : chrono::DateTime<chrono::Utc>,
+    // Frames delayed by the airtime limiter are held until then
+    hold_until : Option<Instant>,
+    data : Vec<u8>,
+}
+
+// A frame waiting in the queue, as listed by /api/tx_queue
+#[derive(Clone, Debug, Serialize)]
+pub struct QueuedFrame {
+    pub id : i64,
+    pub source : TxSource,
+    pub priority : Priority,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub queued_at : chrono::DateTime<chrono::Utc>,
+    // Seconds until the frame may be sent, if it is held back
+    pub held_seconds : Option<u64>,
+    pub len : usize,
+}
+
+/* Frames waiting for the radio. The frame with the highest priority is sent
+ * first, in the order they were queued for the same priority. The queue
+ * outlives the radio backend, so nothing is lost when the radio is reset. */
+#[derive(Clone, Default)]
+pub struct TxQueue {
+    entries : Arc<Mutex<Vec<Entry>>>,
+    notify : Arc<Notify>,
+}
+
+impl TxQueue {
+    /* Add a frame, fully encoded with its length prefix. When the queue is full,
+     * the newest frame of the lowest priority makes room if it has a lower
+     * priority than the new one. Returns the id of that frame. */
+    pub fn push(&self, id: i64, source: TxSource, data: Vec<u8>, hold_until: Option<Instant>) -> anyhow::Result<Option<i64>> {
+        let priority = Priority::of(source);
+        let mut entries = self.entries.lock().unwrap();
+
+        let mut evicted = None;
+        if entries.len() >= CAPACITY {
+            let lowest = entries.iter()
+                .enumerate()
+                .max_by_key(|(i, e)| (e.priority, *i))
+                .map(|(i, e)| (i, e.priority));
+
+            match lowest {
+                Some((i, p)) if p > priority => evicted = Some(entries.remove(i).id),
+                _ => anyhow::bail!("Transmit queue full"),
+            }
+        }
+
+        entries.push(Entry {
+            id,
+            source,
+            priority,
+            queued_at: Instant::now(),
+            queued_at_utc: chrono::Utc::now(),
+            hold_until,
+            data,
+        });
+        drop(entries);
+
+        self.notify.notify_one();
+        Ok(evicted)
+    }
+
+    // Next frame ready to be sent, if any
+    pub fn try_pop(&self) -> Option<TxFrame> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        let (i, _) = entries.iter()
+            .enumerate()
+            .filter(|(_, e)| e.hold_until.map_or(true, |t| t <= now))
+            .min_by_key(|(i, e)| (e.priority, *i))?;
+
+        let entry = entries.remove(i);
+        Some(TxFrame { id: entry.id, data: entry.data })
+    }
+
+    // Wait for the next frame ready to be sent
+    pub async fn pop(&self) -> TxFrame {
+        loop {
+            if let Some(frame) = self.try_pop() {
+                return frame;
+            }
+
+            // Held frames become ready without any notification
+            let next_release = self.entries.lock().unwrap().iter()
+                .filter_map(|e| e.hold_until)
+                .min();
+
+            match next_release {
+                Some(t) => {
+                    let _ = tokio::time::timeout_at(t.into(), self.notify.notified()).await;
+                },
+                None => self.notify.notified().await,
+            }
+        }
+    }
+
+    // Remove a frame that was not sent yet. Returns false if it is not in the queue
+    pub fn cancel(&self, id: i64) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|e| e.id != id);
+        entries.len() != len
+    }
+
+    // Remove the frames that waited too long, and return their ids.
+    // Held frames only start to age once they may be sent
+    pub fn take_expired(&self) -> Vec<i64> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.entries.lock().unwrap().retain(|e| {
+            let ready_at = e.hold_until.unwrap_or(e.queued_at);
+            let keep = now.saturating_duration_since(ready_at) < e.priority.max_age();
+            if !keep {
+                expired.push(e.id);
+            }
+            keep
+        });
+        expired
+    }
+
+    // Frames in the order they will be sent
+    pub fn list(&self) -> Vec<QueuedFrame> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+
+        let mut order : Vec<usize> = (0..entries.len()).collect();
+        order.sort_by_key(|&i| (entries[i].priority, i));
+
+        order.into_iter()
+            .map(|i| &entries[i])
+            .map(|e| QueuedFrame {
+                id: e.id,
+                source: e.source,
+                priority: e.priority,
+                queued_at: e.queued_at_utc,
+                held_seconds: e.hold_until
+                    .filter(|t| *t > now)
+                    .map(|t| t.duration_since(now).as_secs()),
+                len: e.data.len(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(queue: &TxQueue, id: i64, source: TxSource) -> anyhow::Result<Option<i64>> {
+        queue.push(id, source, vec![1, 0, id as u8], None)
+    }
+
+    fn pop_all(queue: &TxQueue) -> Vec<i64> {
+        std::iter::from_fn(|| queue.try_pop()).map(|f| f.id).collect()
+    }
+
+    fn ago(seconds: u64) -> Instant {
+        Instant::now().checked_sub(Duration::from_secs(seconds)).unwrap()
+    }
+
+    #[test]
+    fn pops_by_priority_then_in_order() {
+        let queue = TxQueue::default();
+        push(&queue, 1, TxSource::Tunnel).unwrap();
+        push(&queue, 2, TxSource::Beacon).unwrap();
+        push(&queue, 3, TxSource::Ui).unwrap();
+        push(&queue, 4, TxSource::Digipeater).unwrap();
+        push(&queue, 5, TxSource::Kiss).unwrap();
+        push(&queue, 6, TxSource::Felinet).unwrap();
+
+        assert_eq!(pop_all(&queue), [3, 5, 2, 4, 6, 1]);
+    }
+
+    #[test]
+    fn evicts_newest_lower_priority_frame_when_full() {
+        let queue = TxQueue::default();
+        for id in 0..CAPACITY as i64 {
+            push(&queue, id, TxSource::Tunnel).unwrap();
+        }
+
+        assert_eq!(push(&queue, 100, TxSource::Beacon).unwrap(), Some(CAPACITY as i64 - 1));
+        // Same priority as the lowest frame queued
+        assert!(push(&queue, 101, TxSource::Tunnel).is_err());
+
+        let ids = pop_all(&queue);
+        assert_eq!(ids.len(), CAPACITY);
+        assert_eq!(ids[0], 100);
+    }
+
+    #[test]
+    fn rejects_lower_priority_frame_when_full() {
+        let queue = TxQueue::default();
+        for id in 0..CAPACITY as i64 {
+            push(&queue, id, TxSource::Ui).unwrap();
+        }
+
+        assert!(push(&queue, 100, TxSource::Tunnel).is_err());
+        assert!(!pop_all(&queue).contains(&100));
+    }
+
+    #[test]
+    fn expires_frames_after_their_max_age() {
+        let queue = TxQueue::default();
+        // Held frames age from the end of their hold time
+        queue.push(1, TxSource::Tunnel, vec![0, 0], Some(ago(31))).unwrap();
+        queue.push(2, TxSource::Tunnel, vec![0, 0], Some(ago(20))).unwrap();
+        queue.push(3, TxSource::Digipeater, vec![0, 0], Some(ago(61))).unwrap();
+        queue.push(4, TxSource::Beacon, vec![0, 0], Some(ago(61))).unwrap();
+        queue.push(5, TxSource::Ui, vec![0, 0], Some(ago(599))).unwrap();
+        queue.push(6, TxSource::Ui, vec![0, 0], Some(ago(601))).unwrap();
+        push(&queue, 7, TxSource::Tunnel).unwrap();
+
+        assert_eq!(queue.take_expired(), [1, 3, 6]);
+        assert_eq!(pop_all(&queue), [5, 4, 2, 7]);
+    }
+
+    #[test]
+    fn cancels_only_the_given_frame() {
+        let queue = TxQueue::default();
+        push(&queue, 1, TxSource::Ui).unwrap();
+        push(&queue, 2, TxSource::Ui).unwrap();
+        push(&queue, 3, TxSource::Ui).unwrap();
+
+        assert!(queue.cancel(2));
+        assert!(!queue.cancel(2));
+        assert!(!queue.cancel(4));
+        assert_eq!(pop_all(&queue), [1, 3]);
+    }
+
+    #[test]
+    fn holds_frames_until_released() {
+        let queue = TxQueue::default();
+        let release = Instant::now() + Duration::from_secs(3600);
+        queue.push(1, TxSource::Ui, vec![0, 0], Some(release)).unwrap();
+        push(&queue, 2, TxSource::Tunnel).unwrap();
+
+        // The frame of lower priority goes first
+        assert_eq!(pop_all(&queue), [2]);
+
+        let listed = queue.list();
+        assert_eq!(listed.len(), 1);
+        assert!(listed[0].held_seconds.is_some_and(|s| s > 3500));
+    }
+
+    #[tokio::test]
+    async fn pop_waits_for_held_frame() {
+        let queue = TxQueue::default();
+        queue.push(1, TxSource::Ui, vec![0, 0], Some(Instant::now() + Duration::from_millis(200))).unwrap();
+
+        let frame = tokio::time::timeout(Duration::from_secs(5), queue.pop()).await.unwrap();
+        assert_eq!(frame.id, 1);
+    }
+}
@@ -8,24 +8,23 @@ use axum::{
     Form,
     Json,
     Router,
-    extract::State,
+    extract::{Path, Query, State},
     extract::{ws::{Message, WebSocket, WebSocketUpgrade}, ConnectInfo},
-    http::StatusCode,
-    response::IntoResponse,
+    http::{header, StatusCode},
+    response::{IntoResponse, Redirect, Response},
     routing::{get, post},
 };
-use chrono::serde::ts_seconds;
+use chrono::serde::{ts_seconds, ts_seconds_option};
 use futures::{StreamExt, SinkExt};
 use log::{debug, info, warn, error};
 use serde::Deserialize;
 use tower_http::services::ServeDir;
 
 use ham_cats::{
-    buffer::Buffer,
-    whisker::{Identification, Destination},
+    whisker::{Identification, Destination, Gps, NodeInfo, Route, RouteHop},
 };
 
-use crate::{config, radio::MAX_PACKET_LEN};
+use crate::{config, db, framing, pcap, radio::MAX_PACKET_LEN, transmit};
 use crate::SharedState;
 
 pub async fn serve(port: u16, shared_state: SharedState) {
@@ -33,6 +32,14 @@ pub async fn serve(port: u16, shared_state: SharedState) {
         .route("/", get(dashboard))
         .route("/chat", get(chat))
         .route("/chat/ws", get(ws_handler))
+        .route("/packet/:id", get(show_packet))
+        .route("/undecodable", get(undecodable))
+        .route("/api/undecodable", get(api_undecodable))
+        .route("/api/radio", get(api_radio))
+        .route("/api/tx_queue", get(api_tx_queue))
+        .route("/api/tx_queue/:id", axum::routing::delete(api_cancel_transmission))
+        .route("/api/export.pcap", get(api_export_pcap))
+        .route("/tx_queue/:id/cancel", post(cancel_transmission))
         .route("/send", get(send))
         .route("/api/send_packet", post(post_packet))
         .route("/settings", get(show_settings).post(post_settings))
@@ -76,7 +83,72 @@ struct DashboardTemplate<'a> {
     conf: config::Config,
     node_startup_time: String,
     num_received_frames: u64,
+    num_undecodable_frames: u64,
     packets: Vec<UIPacket>,
+    transmissions: Vec<UITransmission>,
+    felinet: crate::felinet::Status,
+    radio: UIRadioStatus,
+    tx_queue: Vec<UIQueuedFrame>,
+    airtime: Vec<UIAirtime>,
+    airtime_delayed: u64,
+    airtime_dropped: u64,
+    num_digipeated: u64,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct UIGps {
+    pub latitude : f64,
+    pub longitude : f64,
+    pub altitude : f32, // m
+    pub max_error : u8, // m
+    pub heading : f64, // degrees
+    pub speed : f32, // m/s
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct UIDestination {
+    pub callsign : String,
+    pub ssid : u8,
+    pub is_ack : bool,
+    pub ack_num : u8,
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UIRouteHopKind {
+    // A station that already repeated the packet
+    Past,
+    // A station that is asked to repeat the packet
+    Future,
+    // The packet went through the internet, e.g. FELINET
+    Internet,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct UIRouteHop {
+    pub kind : UIRouteHopKind,
+    pub callsign : Option<String>,
+    pub ssid : Option<u8>,
+    pub rssi : Option<f64>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct UIRoute {
+    pub max_hops : u8,
+    pub hops : Vec<UIRouteHop>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct UINodeInfo {
+    pub hardware_id : Option<u16>,
+    pub software_id : Option<u8>,
+    pub uptime : Option<u32>, // s
+    pub antenna_height : Option<u8>, // m
+    pub antenna_gain : Option<f64>, // dBi
+    pub tx_power : Option<f64>, // dBm
+    pub voltage : Option<f64>, // V
+    pub xcvr_temperature : Option<i8>, // °C
+    pub battery_charge : Option<f64>, // %
 }
 
 #[derive(Clone, serde::Serialize)]
@@ -86,8 +158,170 @@ pub struct UIPacket {
 
     pub from_callsign : String,
     pub from_ssid : u8,
+    pub icon : u16,
 
     pub comment : Option<String>,
+    #[serde(with = "ts_seconds_option")]
+    pub timestamp : Option<chrono::DateTime<chrono::Utc>>,
+    pub gps : Option<UIGps>,
+    pub destinations : Vec<UIDestination>,
+    pub route : Option<UIRoute>,
+    pub node_info : Option<UINodeInfo>,
+    // Hex encoded content of the Arbitrary whiskers
+    pub arbitrary : Vec<String>,
+
+    pub rssi : Option<f64>,
+    pub direction : db::Direction,
+    pub freq : Option<u32>, // kHz
+    pub decode_status : db::DecodeStatus,
+
+    pub id : i64,
+    // Set if this is another copy of the packet with that id
+    pub duplicate_of : Option<i64>,
+    // For received packets, the last hop of every copy that was heard
+    pub heard_via : Vec<String>,
+}
+
+pub fn hex_string(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl UIGps {
+    fn new(gps: &Gps) -> Self {
+        Self {
+            latitude: gps.latitude(),
+            longitude: gps.longitude(),
+            altitude: gps.altitude.to_f32(),
+            max_error: gps.max_error,
+            heading: gps.heading(),
+            speed: gps.speed.to_f32(),
+        }
+    }
+}
+
+impl UIRoute {
+    fn new(route: &Route) -> Self {
+        let hops = route.iter()
+            .map(|hop| match hop {
+                RouteHop::Past(past) => UIRouteHop {
+                    kind: UIRouteHopKind::Past,
+                    callsign: Some(past.identity().callsign().to_owned()),
+                    ssid: Some(past.identity().ssid()),
+                    rssi: past.rssi(),
+                },
+                RouteHop::Future(identity) => UIRouteHop {
+                    kind: UIRouteHopKind::Future,
+                    callsign: Some(identity.callsign().to_owned()),
+                    ssid: Some(identity.ssid()),
+                    rssi: None,
+                },
+                RouteHop::Internet => UIRouteHop {
+                    kind: UIRouteHopKind::Internet,
+                    callsign: None,
+                    ssid: None,
+                    rssi: None,
+                },
+            })
+            .collect();
+
+        Self {
+            max_hops: route.max_hops,
+            hops,
+        }
+    }
+
+    fn hops_str(&self) -> String {
+        self.hops.iter()
+            .map(|hop| match hop.kind {
+                UIRouteHopKind::Internet => "internet".to_owned(),
+                UIRouteHopKind::Past => {
+                    let rssi = match hop.rssi {
+                        Some(rssi) => format!(" ({rssi:.0} dBm)"),
+                        None => "".to_owned(),
+                    };
+                    format!("{}-{}{}",
+                        hop.callsign.as_deref().unwrap_or_default(),
+                        hop.ssid.unwrap_or_default(),
+                        rssi)
+                },
+                UIRouteHopKind::Future => format!("{}-{} (requested)",
+                        hop.callsign.as_deref().unwrap_or_default(),
+                        hop.ssid.unwrap_or_default()),
+            })
+            .collect::<Vec<_>>()
+            .join(" > ")
+    }
+}
+
+impl UINodeInfo {
+    fn new(node_info: &NodeInfo) -> Self {
+        Self {
+            hardware_id: node_info.hardware_id(),
+            software_id: node_info.software_id(),
+            uptime: node_info.uptime(),
+            antenna_height: node_info.antenna_height(),
+            antenna_gain: node_info.antenna_gain(),
+            tx_power: node_info.tx_power(),
+            voltage: node_info.voltage(),
+            xcvr_temperature: node_info.xcvr_temperature(),
+            battery_charge: node_info.battery_charge(),
+        }
+    }
+
+    // Only the fields that are present, e.g. "uptime 3600s, tx power 30 dBm"
+    fn fields_str(&self) -> String {
+        let mut fields = Vec::new();
+        if let Some(v) = self.hardware_id { fields.push(format!("hardware {v:#06x}")); }
+        if let Some(v) = self.software_id { fields.push(format!("software {v:#04x}")); }
+        if let Some(v) = self.uptime { fields.push(format!("uptime {v}s")); }
+        if let Some(v) = self.antenna_height { fields.push(format!("antenna height {v} m")); }
+        if let Some(v) = self.antenna_gain { fields.push(format!("antenna gain {v:.1} dBi")); }
+        if let Some(v) = self.tx_power { fields.push(format!("tx power {v:.1} dBm")); }
+        if let Some(v) = self.voltage { fields.push(format!("voltage {v:.2} V")); }
+        if let Some(v) = self.xcvr_temperature { fields.push(format!("transceiver {v} °C")); }
+        if let Some(v) = self.battery_charge { fields.push(format!("battery {v:.0}%")); }
+        fields.join(", ")
+    }
+}
+
+// The last station that repeated the packet, or "direct"
+fn last_hop(packet: &ham_cats::packet::Packet<'_, MAX_PACKET_LEN>) -> String {
+    let mut via = "direct".to_owned();
+    if let Some(route) = packet.route() {
+        for hop in route.iter() {
+            match hop {
+                RouteHop::Past(past) => {
+                    let identity = past.identity();
+                    via = format!("{}-{}", identity.callsign(), identity.ssid());
+                },
+                RouteHop::Internet => via = "internet".to_owned(),
+                RouteHop::Future(_) => break,
+            }
+        }
+    }
+    via
+}
+
+/* Convert packets from the database for display, merging the duplicates
+ * into the first copy. Duplicates of packets not in the list are dropped. */
+fn collapse_duplicates(db_packets: &[db::Packet]) -> Vec<UIPacket> {
+    let mut packets : Vec<UIPacket> = db_packets.iter()
+        .filter(|p| p.duplicate_of.is_none())
+        .filter_map(UIPacket::from_db_packet)
+        .collect();
+
+    for duplicate in db_packets.iter().filter(|p| p.duplicate_of.is_some()) {
+        if let Some(original) = packets.iter_mut().find(|p| Some(p.id) == duplicate.duplicate_of) {
+            if let Some(d) = UIPacket::from_db_packet(duplicate) {
+                original.heard_via.extend(d.heard_via);
+            }
+        }
+    }
+
+    packets
 }
 
 impl UIPacket {
@@ -95,7 +329,75 @@ impl UIPacket {
         self.received_at.to_string()
     }
 
-    fn from_db_packet(db_packet: &crate::db::Packet) -> Option<Self> {
+    fn is_transmitted(&self) -> bool {
+        self.direction == db::Direction::Transmitted
+    }
+
+    fn rssi_str(&self) -> String {
+        match self.rssi {
+            Some(rssi) => format!("{rssi:.1} dBm"),
+            None => "".to_owned(),
+        }
+    }
+
+    fn freq_str(&self) -> String {
+        match self.freq {
+            Some(freq) => format!("{:.3} MHz", freq as f64 / 1000.0),
+            None => "".to_owned(),
+        }
+    }
+
+    fn timestamp_str(&self) -> String {
+        match self.timestamp {
+            Some(t) => format!("{} UTC", t.format("%Y-%m-%d %H:%M:%S")),
+            None => "".to_owned(),
+        }
+    }
+
+    // The comment, or the other whiskers of packets without one, as shown in the chat
+    fn summary(&self) -> String {
+        if let Some(comment) = &self.comment {
+            return comment.clone();
+        }
+
+        let mut parts = Vec::new();
+        if let Some(gps) = &self.gps {
+            parts.push(format!("[position {:.5}, {:.5}]", gps.latitude, gps.longitude));
+        }
+        if self.node_info.is_some() {
+            parts.push("[node info]".to_owned());
+        }
+        if !self.destinations.is_empty() {
+            parts.push(format!("[to {}]", self.destinations_str()));
+        }
+        if parts.is_empty() {
+            parts.push("[no comment]".to_owned());
+        }
+        parts.join(" ")
+    }
+
+    fn destinations_str(&self) -> String {
+        self.destinations.iter()
+            .map(|d| if d.is_ack {
+                format!("{}-{} (ack {})", d.callsign, d.ssid, d.ack_num)
+            }
+            else {
+                format!("{}-{}", d.callsign, d.ssid)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn heard_str(&self) -> String {
+        match self.heard_via.as_slice() {
+            [] => "".to_owned(),
+            [via] if via == "direct" => "".to_owned(),
+            [via] => format!("via {via}"),
+            all => format!("heard {} times via {}", all.len(), all.join(", ")),
+        }
+    }
+
+    pub fn from_db_packet(db_packet: &db::Packet) -> Option<Self> {
         let mut buf = [0; MAX_PACKET_LEN];
         match ham_cats::packet::Packet::fully_decode(&db_packet.content, &mut buf) {
             Ok(p) => {
@@ -107,11 +409,47 @@ impl UIPacket {
                         Err(_) => None,
                     };
 
+                    let heard_via = match db_packet.direction {
+                        db::Direction::Received => vec![last_hop(&p)],
+                        db::Direction::Transmitted => vec![],
+                    };
+
+                    let timestamp = p.timestamp()
+                        .and_then(|t| t.unix_time().try_into().ok())
+                        .and_then(|t| chrono::DateTime::from_timestamp(t, 0));
+
+                    let destinations = p.destination_iter()
+                        .map(|d| UIDestination {
+                            callsign: d.callsign().to_owned(),
+                            ssid: d.ssid(),
+                            is_ack: d.is_ack(),
+                            ack_num: d.ack_num(),
+                        })
+                        .collect();
+
+                    let arbitrary = p.arbitrary_iter()
+                        .map(|a| hex_string(a.0.as_slice()))
+                        .collect();
+
                     Some(UIPacket {
                         received_at : db_packet.received_at,
                         from_callsign : ident.callsign.to_string(),
                         from_ssid : ident.ssid,
-                        comment
+                        icon : ident.icon,
+                        comment,
+                        timestamp,
+                        gps : p.gps().as_ref().map(UIGps::new),
+                        destinations,
+                        route : p.route().as_ref().map(UIRoute::new),
+                        node_info : p.node_info().as_ref().map(UINodeInfo::new),
+                        arbitrary,
+                        rssi : db_packet.rssi,
+                        direction : db_packet.direction,
+                        freq : db_packet.freq,
+                        decode_status : db_packet.decode_status,
+                        id : db_packet.id,
+                        duplicate_of : db_packet.duplicate_of,
+                        heard_via,
                     })
                 }
                 else {
@@ -126,21 +464,217 @@ impl UIPacket {
     }
 }
 
+fn source_name(source: db::TxSource) -> &'static str {
+    match source {
+        db::TxSource::Ui => "Sent from the UI",
+        db::TxSource::Beacon => "Beacon",
+        db::TxSource::Digipeater => "Digipeated",
+        db::TxSource::Felinet => "From FELINET",
+        db::TxSource::Tunnel => "Tunnel",
+        db::TxSource::Kiss => "KISS client",
+    }
+}
+
+// A frame waiting in the transmit queue
+struct UIQueuedFrame {
+    frame : crate::tx_queue::QueuedFrame,
+}
+
+impl UIQueuedFrame {
+    fn queued_at_iso(&self) -> String {
+        self.frame.queued_at.to_string()
+    }
+
+    fn source_str(&self) -> &'static str {
+        source_name(self.frame.source)
+    }
+
+    fn held_str(&self) -> String {
+        match self.frame.held_seconds {
+            Some(s) => format!("held {s} s for airtime"),
+            None => "".to_owned(),
+        }
+    }
+}
+
+// Airtime used during the last hour
+struct UIAirtime {
+    name : &'static str,
+    used_seconds : f64,
+    limit_seconds : Option<u64>,
+}
+
+impl UIAirtime {
+    fn new(usage: &crate::airtime::Usage) -> Self {
+        Self {
+            name: usage.source.map(source_name).unwrap_or("Total"),
+            used_seconds: usage.used.as_secs_f64(),
+            limit_seconds: usage.limit.map(|l| l.as_secs()),
+        }
+    }
+
+    fn used_str(&self) -> String {
+        match self.limit_seconds {
+            Some(limit) => format!("{:.1} of {limit} s", self.used_seconds),
+            None => format!("{:.1} s", self.used_seconds),
+        }
+    }
+}
+
+// An entry of the transmit log
+struct UITransmission {
+    id : i64,
+    queued_at : chrono::DateTime<chrono::Utc>,
+    updated_at : chrono::DateTime<chrono::Utc>,
+    source : db::TxSource,
+    state : db::TxState,
+    error : Option<String>,
+    frame_id : Option<i64>,
+}
+
+impl UITransmission {
+    fn new(transmission: &db::Transmission) -> Self {
+        Self {
+            id: transmission.id,
+            queued_at: transmission.queued_at,
+            updated_at: transmission.updated_at,
+            source: transmission.source,
+            state: transmission.state,
+            error: transmission.error.clone(),
+            frame_id: transmission.frame_id,
+        }
+    }
+
+    fn queued_at_iso(&self) -> String {
+        self.queued_at.to_string()
+    }
+
+    fn updated_at_iso(&self) -> String {
+        self.updated_at.to_string()
+    }
+
+    fn source_str(&self) -> &'static str {
+        source_name(self.source)
+    }
+
+    fn state_str(&self) -> &'static str {
+        match self.state {
+            db::TxState::Queued => "queued",
+            db::TxState::Transmitting => "transmitting",
+            db::TxState::Sent => "sent",
+            db::TxState::Failed => "failed",
+            db::TxState::TimedOut => "timed out",
+            db::TxState::Cancelled => "cancelled",
+            db::TxState::Expired => "expired",
+        }
+    }
+}
+
+// Size of the temperature plot on the dashboard
+const PLOT_WIDTH : f32 = 300.0;
+const PLOT_HEIGHT : f32 = 60.0;
+
+#[derive(serde::Serialize)]
+struct UIRadioStatus {
+    #[serde(flatten)]
+    status : crate::radio::Status,
+    seconds_since_last_rx : Option<i64>,
+    csma : crate::radio::csma::Stats,
+}
+
+impl UIRadioStatus {
+    fn new(status: crate::radio::Status, csma: crate::radio::csma::Stats) -> Self {
+        let seconds_since_last_rx = status.last_received_at
+            .map(|t| (chrono::Utc::now() - t).num_seconds());
+        Self { status, seconds_since_last_rx, csma }
+    }
+
+    fn backend_str(&self) -> &'static str {
+        self.status.backend.unwrap_or("none")
+    }
+
+    fn temperature_str(&self) -> String {
+        match self.status.temperature {
+            Some(t) => format!("{t:.1} °C"),
+            None => "unknown".to_owned(),
+        }
+    }
+
+    fn last_rx_str(&self) -> String {
+        match self.seconds_since_last_rx {
+            Some(s) => format!("{s} s ago"),
+            None => "never".to_owned(),
+        }
+    }
+
+    fn temperature_range(&self) -> (f32, f32) {
+        let history = &self.status.temperature_history;
+        let min = history.iter().map(|s| s.celsius).fold(f32::INFINITY, f32::min);
+        let max = history.iter().map(|s| s.celsius).fold(f32::NEG_INFINITY, f32::max);
+        (min, max)
+    }
+
+    fn temperature_range_str(&self) -> String {
+        let (min, max) = self.temperature_range();
+        format!("{min:.1} to {max:.1} °C")
+    }
+
+    // SVG polyline of the temperature history, scaled to the plot
+    fn temperature_points(&self) -> String {
+        let history = &self.status.temperature_history;
+        let (min, max) = self.temperature_range();
+        // Keep a flat line in the middle of the plot
+        let span = (max - min).max(1.0);
+        let low = (min + max - span) / 2.0;
+        let step = PLOT_WIDTH / (history.len().max(2) - 1) as f32;
+
+        history.iter()
+            .enumerate()
+            .map(|(i, s)| format!("{:.1},{:.1}", i as f32 * step, PLOT_HEIGHT - (s.celsius - low) / span * PLOT_HEIGHT))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
 async fn dashboard(State(state): State<SharedState>) -> DashboardTemplate<'static> {
-    let (conf, mut db, node_startup_time) = {
+    let (conf, mut db, node_startup_time, felinet, radio, csma, num_digipeated) = {
+        let st = state.lock().unwrap();
+        (st.config(), st.db.clone(), st.start_time, st.felinet.clone(), st.radio.clone(), st.csma.stats(), st.num_digipeated)
+    };
+
+    let (tx_queue, airtime, airtime_delayed, airtime_dropped) = {
         let st = state.lock().unwrap();
-        (st.conf.clone(), st.db.clone(), st.start_time.clone())
+        let tx_queue = st.transmit_queue.list().into_iter()
+            .map(|frame| UIQueuedFrame { frame })
+            .collect();
+        let usage = st.airtime.usage(&conf.airtime);
+        (tx_queue, usage.iter().map(UIAirtime::new).collect(), st.airtime.num_delayed, st.airtime.num_dropped)
     };
 
-    let packets = match db.get_most_recent_packets(10).await {
+    let mut db_packets = match db.get_most_recent_packets(10).await {
         Ok(v) => v,
         Err(e) => {
             warn!("Dashboard will have empty packet list: {}", e);
             Vec::new()
         },
-    }.iter()
-    .filter_map(|p| UIPacket::from_db_packet(p))
-    .collect();
+    };
+
+    if let Some(first_id) = db_packets.iter().map(|p| p.id).min() {
+        match db.get_duplicates_since_id(first_id).await {
+            Ok(duplicates) => db_packets.extend(duplicates),
+            Err(e) => warn!("Dashboard will not show duplicates: {}", e),
+        }
+    }
+
+    let packets = collapse_duplicates(&db_packets);
+
+    let transmissions = match db.get_most_recent_transmissions(10).await {
+        Ok(v) => v.iter().map(UITransmission::new).collect(),
+        Err(e) => {
+            warn!("Dashboard will have empty transmission list: {}", e);
+            Vec::new()
+        },
+    };
 
     let node_startup_time = format!("{} UTC",
         node_startup_time.format("%Y-%m-%d %H:%M:%S"));
@@ -150,11 +684,50 @@ async fn dashboard(State(state): State<SharedState>) -> DashboardTemplate<'stati
         conf,
         page: ActivePage::Dashboard,
         num_received_frames : db.get_num_received_frames(),
+        num_undecodable_frames : db.get_num_undecodable_frames(),
         node_startup_time,
         packets,
+        transmissions,
+        felinet,
+        radio: UIRadioStatus::new(radio, csma),
+        tx_queue,
+        airtime,
+        airtime_delayed,
+        airtime_dropped,
+        num_digipeated,
+    }
+}
+
+async fn api_tx_queue(State(state): State<SharedState>) -> Json<Vec<crate::tx_queue::QueuedFrame>> {
+    let transmit_queue = state.lock().unwrap().transmit_queue.clone();
+    Json(transmit_queue.list())
+}
+
+async fn api_cancel_transmission(State(state): State<SharedState>, Path(id): Path<i64>) -> StatusCode {
+    if transmit::cancel(&state, id).await {
+        StatusCode::NO_CONTENT
+    }
+    else {
+        StatusCode::NOT_FOUND
     }
 }
 
+// Cancel button of the dashboard
+async fn cancel_transmission(State(state): State<SharedState>, Path(id): Path<i64>) -> Redirect {
+    if !transmit::cancel(&state, id).await {
+        info!("Transmission {id} was no longer queued");
+    }
+    Redirect::to("/")
+}
+
+async fn api_radio(State(state): State<SharedState>) -> Json<UIRadioStatus> {
+    let (radio, csma) = {
+        let st = state.lock().unwrap();
+        (st.radio.clone(), st.csma.stats())
+    };
+    Json(UIRadioStatus::new(radio, csma))
+}
+
 #[derive(Template)]
 #[template(path = "chat.html")]
 struct ChatTemplate<'a> {
@@ -168,7 +741,7 @@ async fn chat(State(state): State<SharedState>) -> ChatTemplate<'static> {
 
     let (conf, mut db) = {
         let st = state.lock().unwrap();
-        (st.conf.clone(), st.db.clone())
+        (st.config(), st.db.clone())
     };
 
     let time_start = SystemTime::now() - Duration::from_secs(6*3600);
@@ -178,11 +751,7 @@ async fn chat(State(state): State<SharedState>) -> ChatTemplate<'static> {
 
     let timestamp_i64 : i64 = timestamp.as_secs().try_into().unwrap();
     let packets = match db.get_packets_since(timestamp_i64).await {
-        Ok(packets) => {
-            packets.iter()
-                .filter_map(|p| UIPacket::from_db_packet(p))
-                .collect()
-        },
+        Ok(packets) => collapse_duplicates(&packets),
         Err(e) => {
             error!("Failed to get packets since TS: {e}");
             vec![]
@@ -197,6 +766,261 @@ async fn chat(State(state): State<SharedState>) -> ChatTemplate<'static> {
     }
 }
 
+// Whisker type identifiers, from the CATS standard
+fn whisker_name(type_id: u8) -> &'static str {
+    match type_id {
+        0x00 => "Identification",
+        0x01 => "Timestamp",
+        0x02 => "GPS",
+        0x03 => "Comment",
+        0x04 => "Route",
+        0x05 => "Destination",
+        0x06 => "Arbitrary",
+        0x07 => "Simplex",
+        0x08 => "Repeater",
+        0x09 => "Node info",
+        _ => "Unknown",
+    }
+}
+
+// Classic hex dump: offset, 16 bytes in hex, and their printable ASCII characters
+fn hex_dump(data: &[u8]) -> Vec<String> {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let ascii : String = chunk.iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                .collect();
+            format!("{:04X}  {:<47}  {}", i * 16, hex_string(chunk), ascii)
+        })
+        .collect()
+}
+
+struct WhiskerSpan {
+    offset: usize,
+    type_id: u8,
+    name: &'static str,
+    len: usize,
+    content: String,
+}
+
+// Each whisker is encoded as type byte, length byte and content
+fn whisker_spans(decoded: &[u8]) -> Vec<WhiskerSpan> {
+    let mut spans = Vec::new();
+    let mut offset = 0;
+    while offset + 2 <= decoded.len() {
+        let type_id = decoded[offset];
+        let len = decoded[offset + 1] as usize;
+        let end = std::cmp::min(offset + 2 + len, decoded.len());
+        spans.push(WhiskerSpan {
+            offset,
+            type_id,
+            name: whisker_name(type_id),
+            len,
+            content: hex_string(&decoded[offset + 2..end]),
+        });
+        offset += 2 + len;
+    }
+    spans
+}
+
+#[derive(Template)]
+#[template(path = "packet.html")]
+struct PacketTemplate<'a> {
+    title: &'a str,
+    page: ActivePage,
+    conf: config::Config,
+    id: i64,
+    received_at: String,
+    direction: db::Direction,
+    rssi: Option<f64>,
+    freq: Option<u32>,
+    duplicate_of: Option<i64>,
+    raw_dump: Vec<String>,
+    raw_len: usize,
+    decode_error: Option<String>,
+    decoded_dump: Vec<String>,
+    whiskers: Vec<WhiskerSpan>,
+    packet: Option<UIPacket>,
+}
+
+impl PacketTemplate<'_> {
+    fn is_transmitted(&self) -> bool {
+        self.direction == db::Direction::Transmitted
+    }
+}
+
+async fn show_packet(
+    State(state): State<SharedState>,
+    Path(id): Path<i64>) -> Result<PacketTemplate<'static>, StatusCode> {
+
+    let (conf, mut db) = {
+        let st = state.lock().unwrap();
+        (st.config(), st.db.clone())
+    };
+
+    let db_packet = match db.get_packet(id).await {
+        Ok(Some(p)) => p,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Failed to get packet {id}: {e}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        },
+    };
+
+    let mut buf = [0; MAX_PACKET_LEN];
+    let (decode_error, decoded_dump, whiskers) =
+        match ham_cats::packet::Packet::fully_decode(&db_packet.content, &mut buf) {
+            Ok(p) => (None, hex_dump(p.encode()), whisker_spans(p.encode())),
+            Err(e) => (Some(e.to_string()), vec![], vec![]),
+        };
+
+    Ok(PacketTemplate {
+        title: "Packet",
+        page: ActivePage::None,
+        conf,
+        id: db_packet.id,
+        received_at: format!("{} UTC", db_packet.received_at.format("%Y-%m-%d %H:%M:%S")),
+        direction: db_packet.direction,
+        rssi: db_packet.rssi,
+        freq: db_packet.freq,
+        duplicate_of: db_packet.duplicate_of,
+        raw_dump: hex_dump(&db_packet.content),
+        raw_len: db_packet.content.len(),
+        decode_error,
+        decoded_dump,
+        whiskers,
+        packet: UIPacket::from_db_packet(&db_packet),
+    })
+}
+
+// A frame that could not be decoded, kept for diagnosing interference and misbehaving stations
+#[derive(Clone, serde::Serialize)]
+struct UIUndecodableFrame {
+    id : i64,
+    #[serde(with = "ts_seconds")]
+    received_at : chrono::DateTime<chrono::Utc>,
+    rssi : Option<f64>,
+    freq : Option<u32>, // kHz
+    decode_error : Option<String>,
+    len : usize,
+    // Hex encoded raw frame
+    content : String,
+}
+
+impl UIUndecodableFrame {
+    fn new(db_packet: &db::Packet) -> Self {
+        Self {
+            id: db_packet.id,
+            received_at: db_packet.received_at,
+            rssi: db_packet.rssi,
+            freq: db_packet.freq,
+            decode_error: db_packet.decode_error.clone(),
+            len: db_packet.content.len(),
+            content: hex_string(&db_packet.content),
+        }
+    }
+
+    fn received_at_str(&self) -> String {
+        format!("{} UTC", self.received_at.format("%Y-%m-%d %H:%M:%S"))
+    }
+
+    fn rssi_str(&self) -> String {
+        match self.rssi {
+            Some(rssi) => format!("{rssi:.1} dBm"),
+            None => String::new(),
+        }
+    }
+
+    fn decode_error_str(&self) -> &str {
+        self.decode_error.as_deref().unwrap_or("unknown")
+    }
+}
+
+const NUM_UNDECODABLE_FRAMES_SHOWN : i64 = 100;
+
+async fn get_undecodable_frames(state: &SharedState) -> Vec<UIUndecodableFrame> {
+    let mut db = state.lock().unwrap().db.clone();
+
+    match db.get_undecodable_packets(NUM_UNDECODABLE_FRAMES_SHOWN).await {
+        Ok(packets) => packets.iter().map(UIUndecodableFrame::new).collect(),
+        Err(e) => {
+            error!("Failed to get undecodable frames: {e}");
+            vec![]
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "undecodable.html")]
+struct UndecodableTemplate<'a> {
+    title: &'a str,
+    page: ActivePage,
+    conf: config::Config,
+    num_undecodable_frames: u64,
+    frames: Vec<UIUndecodableFrame>,
+}
+
+async fn undecodable(State(state): State<SharedState>) -> UndecodableTemplate<'static> {
+    let (conf, num_undecodable_frames) = {
+        let st = state.lock().unwrap();
+        (st.config(), st.db.get_num_undecodable_frames())
+    };
+
+    UndecodableTemplate {
+        title: "Undecodable frames",
+        page: ActivePage::None,
+        conf,
+        num_undecodable_frames,
+        frames: get_undecodable_frames(&state).await,
+    }
+}
+
+async fn api_undecodable(State(state): State<SharedState>) -> Json<Vec<UIUndecodableFrame>> {
+    Json(get_undecodable_frames(&state).await)
+}
+
+// Range of the export, in unix time. Everything stored if not given or empty
+#[derive(Deserialize)]
+struct ExportRange {
+    #[serde(default)]
+    from: String,
+    #[serde(default)]
+    to: String,
+}
+
+impl ExportRange {
+    fn bounds(&self) -> anyhow::Result<(i64, i64)> {
+        let from = empty_string_to_none(&self.from).context("Invalid start of the range")?;
+        let to = empty_string_to_none(&self.to).context("Invalid end of the range")?;
+        Ok((from.unwrap_or(0), to.unwrap_or(i64::MAX)))
+    }
+}
+
+async fn api_export_pcap(State(state): State<SharedState>, Query(range): Query<ExportRange>) -> Response {
+    let (from, to) = match range.bounds() {
+        Ok(bounds) => bounds,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response(),
+    };
+
+    let mut db = state.lock().unwrap().db.clone();
+
+    match db.get_packets_between(from, to).await {
+        Ok(packets) => {
+            info!("Exporting {} frames to pcapng", packets.len());
+            let headers = [
+                (header::CONTENT_TYPE, "application/x-pcapng"),
+                (header::CONTENT_DISPOSITION, "attachment; filename=\"cats-radio-node.pcapng\""),
+            ];
+            (headers, pcap::export(&packets)).into_response()
+        },
+        Err(e) => {
+            warn!("Failed to get frames for export: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        },
+    }
+}
+
 async fn ws_handler(
     State(state): State<SharedState>,
     ws: WebSocketUpgrade,
@@ -301,7 +1125,7 @@ struct SendTemplate<'a> {
 async fn send(State(state): State<SharedState>) -> SendTemplate<'static> {
     SendTemplate {
         title: "Send",
-        conf: state.lock().unwrap().conf.clone(),
+        conf: state.lock().unwrap().config(),
         page: ActivePage::Send,
     }
 }
@@ -340,47 +1164,24 @@ fn build_packet(config: config::Config, payload: ApiSendPacket) -> anyhow::Resul
             .map_err(|e| anyhow!("Could not add destination to packet: {e}"))?;
     }
 
-    let mut buf2 = [0; crate::radio::MAX_PACKET_LEN];
-    let mut data = Buffer::new_empty(&mut buf2);
-    pkt.fully_encode(&mut data)
-        .map_err(|e| anyhow!("Could not encode packet: {e}"))?;
-
-    Ok(data.to_vec())
+    framing::encode_packet(pkt)
 }
 
 async fn post_packet(State(state): State<SharedState>, Json(payload): Json<ApiSendPacket>) -> StatusCode {
-    let (config, transmit_queue, mut db, ws_broadcast) = {
-        let s = state.lock().unwrap();
-        (s.conf.clone(), s.transmit_queue.clone(), s.db.clone(), s.ws_broadcast.clone())
-    };
+    let config = state.lock().unwrap().config();
 
     info!("send_packet {:?}", payload);
 
-    let m = UIPacket {
-        received_at: chrono::Utc::now(),
-        from_callsign: config.callsign.to_string(),
-        from_ssid: config.ssid,
-        comment: payload.comment.clone(),
-    };
-
     match build_packet(config, payload) {
         Ok(p) => {
             info!("Built packet of {} bytes", p.len());
 
-            match ws_broadcast.send(m) {
-                Ok(num) => debug!("Send own WS message to {num}"),
-                Err(_) => debug!("No WS receivers currently"),
-            }
-
-            match transmit_queue.send(p.clone()).await {
-                Ok(()) => {
-                    if let Err(e) = db.store_packet(&p[2..]).await {
-                        warn!("Failed to write outgoing packet to sqlite: {}", e);
-                    }
-
-                    StatusCode::OK
+            match transmit::queue(&state, p, db::TxSource::Ui).await {
+                Ok(_) => StatusCode::OK,
+                Err(e) => {
+                    warn!("Failed to queue outgoing packet: {e}");
+                    StatusCode::BAD_REQUEST
                 },
-                Err(_) => StatusCode::BAD_REQUEST,
             }
         },
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
@@ -399,7 +1200,7 @@ async fn show_settings(State(state): State<SharedState>) -> SettingsTemplate<'st
     SettingsTemplate {
         title: "Settings",
         page: ActivePage::Settings,
-        conf: state.lock().unwrap().conf.clone(),
+        conf: state.lock().unwrap().config(),
     }
 }
 
@@ -409,6 +1210,7 @@ struct FormConfig {
     callsign: String,
     ssid: String,
     icon: String,
+    ui_port: u16,
 
     // felinet
     // felinet_enabled is either "on" or absent.
@@ -416,6 +1218,7 @@ struct FormConfig {
     // "If the value attribute was omitted, the default value for the checkbox is `on` [...]"
     felinet_enabled: Option<String>,
     address: String,
+    felinet_downlink: Option<String>,
 
     // beacon
     period_seconds: config::DurationSeconds,
@@ -432,6 +1235,48 @@ struct FormConfig {
     tunnel_enabled: Option<String>,
     local_ip: String,
     netmask: String,
+
+    // digipeater
+    digipeater_enabled: Option<String>,
+    digipeater_max_hops: u8,
+    only_callsigns: String,
+    ignore_callsigns: String,
+
+    // radio
+    radio_backend: String,
+    spi_bus: u8,
+    spi_slave_select: u8,
+    spi_clock_hz: u32,
+    sdn_pin: u8,
+    cs_pin: u8,
+    irq_pin: String,
+    slot_time_ms: u16,
+    persistence: u8,
+    max_deferral_ms: u32,
+    simulated_busy_percent: u8,
+    udp_rx_address: String,
+    udp_tx_address: String,
+
+    // kiss
+    kiss_enabled: Option<String>,
+    kiss_address: String,
+
+    // airtime
+    airtime_enabled: Option<String>,
+    max_seconds_per_hour: u32,
+    max_delay_seconds: config::DurationSeconds,
+    ui_seconds_per_hour: String,
+    beacon_seconds_per_hour: String,
+    digipeater_seconds_per_hour: String,
+    felinet_seconds_per_hour: String,
+    tunnel_seconds_per_hour: String,
+    kiss_seconds_per_hour: String,
+
+    // capture
+    capture_enabled: Option<String>,
+    capture_path: String,
+    max_file_size_kb: u32,
+    max_files: u32,
 }
 
 fn empty_string_to_none<T: FromStr + Sync>(value: &str) -> Result<Option<T>, T::Err> {
@@ -443,18 +1288,28 @@ fn empty_string_to_none<T: FromStr + Sync>(value: &str) -> Result<Option<T>, T::
     }
 }
 
+fn comma_separated_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_owned())
+        .collect()
+}
+
 impl TryFrom<FormConfig> for config::Config {
     type Error = anyhow::Error;
 
     fn try_from(value: FormConfig) -> Result<Self, Self::Error> {
-        Ok(config::Config {
+        let conf = config::Config {
             freq: value.freq.parse()?,
             callsign: value.callsign,
             ssid: value.ssid.parse()?,
             icon: value.icon.parse()?,
+            ui_port: value.ui_port,
             felinet: config::FelinetConfig {
                 enabled: value.felinet_enabled.is_some(),
                 address: value.address,
+                downlink: value.felinet_downlink.is_some(),
             },
             beacon: config::BeaconConfig {
                 period_seconds: value.period_seconds,
@@ -472,7 +1327,53 @@ impl TryFrom<FormConfig> for config::Config {
                 local_ip: value.local_ip,
                 netmask: value.netmask,
             },
-        })
+            digipeater: config::DigipeaterConfig {
+                enabled: value.digipeater_enabled.is_some(),
+                max_hops: value.digipeater_max_hops,
+                only_callsigns: comma_separated_list(&value.only_callsigns),
+                ignore_callsigns: comma_separated_list(&value.ignore_callsigns),
+            },
+            radio: config::RadioConfig {
+                backend: value.radio_backend.parse()?,
+                spi_bus: value.spi_bus,
+                spi_slave_select: value.spi_slave_select,
+                spi_clock_hz: value.spi_clock_hz,
+                sdn_pin: value.sdn_pin,
+                cs_pin: value.cs_pin,
+                irq_pin: empty_string_to_none(&value.irq_pin)?,
+                csma: config::CsmaConfig {
+                    slot_time_ms: value.slot_time_ms,
+                    persistence: value.persistence,
+                    max_deferral_ms: value.max_deferral_ms,
+                },
+                simulated_busy_percent: value.simulated_busy_percent,
+                udp_rx_address: value.udp_rx_address,
+                udp_tx_address: value.udp_tx_address,
+            },
+            kiss: config::KissConfig {
+                enabled: value.kiss_enabled.is_some(),
+                address: value.kiss_address,
+            },
+            airtime: config::AirtimeConfig {
+                enabled: value.airtime_enabled.is_some(),
+                max_seconds_per_hour: value.max_seconds_per_hour,
+                max_delay_seconds: value.max_delay_seconds,
+                ui_seconds_per_hour: empty_string_to_none(&value.ui_seconds_per_hour)?,
+                beacon_seconds_per_hour: empty_string_to_none(&value.beacon_seconds_per_hour)?,
+                digipeater_seconds_per_hour: empty_string_to_none(&value.digipeater_seconds_per_hour)?,
+                felinet_seconds_per_hour: empty_string_to_none(&value.felinet_seconds_per_hour)?,
+                tunnel_seconds_per_hour: empty_string_to_none(&value.tunnel_seconds_per_hour)?,
+                kiss_seconds_per_hour: empty_string_to_none(&value.kiss_seconds_per_hour)?,
+            },
+            capture: config::CaptureConfig {
+                enabled: value.capture_enabled.is_some(),
+                path: value.capture_path,
+                max_file_size_kb: value.max_file_size_kb,
+                max_files: value.max_files,
+            },
+        };
+        conf.validate()?;
+        Ok(conf)
     }
 }
 
@@ -485,6 +1386,77 @@ struct SettingsAppliedTemplate<'a> {
     ok: bool,
     error_message: &'a str,
     error_reason: String,
+    // Changes that took effect immediately
+    applied: Vec<String>,
+    // Changes that take effect after a restart
+    restart_needed: Vec<&'a str>,
+}
+
+/* What the new configuration changes, applied right away or after a restart.
+ * radio_tunable is set if an RF radio is in use and working, which retunes
+ * to the new frequency right away. */
+fn describe_changes(old: &config::Config, new: &config::Config, radio_tunable: bool) -> (Vec<String>, Vec<&'static str>) {
+    let mut applied = Vec::new();
+    let mut restart_needed = Vec::new();
+
+    if old.freq != new.freq {
+        if new.freq == 0 {
+            restart_needed.push("Frequency of zero, which disables the radio");
+        }
+        else if radio_tunable {
+            applied.push(format!("Radio tuned to {} kHz", new.freq));
+        }
+        else {
+            applied.push(format!("Frequency {} kHz saved, applied when the radio is available", new.freq));
+        }
+    }
+
+    if (&old.callsign, old.ssid, old.icon) != (&new.callsign, new.ssid, new.icon) {
+        applied.push(format!("Identification {}-{}", new.callsign, new.ssid));
+    }
+
+    if old.felinet != new.felinet {
+        applied.push(if new.felinet.enabled { "FELINET igate (reconnecting)" } else { "FELINET igate disabled" }.to_owned());
+    }
+
+    if old.beacon != new.beacon {
+        applied.push("Beacon".to_owned());
+    }
+
+    if old.tunnel != new.tunnel {
+        applied.push(if new.tunnel.enabled { "Tunnel (TUN device recreated)" } else { "Tunnel disabled" }.to_owned());
+    }
+
+    if old.digipeater != new.digipeater {
+        applied.push("Digipeater".to_owned());
+    }
+
+    if old.kiss != new.kiss {
+        applied.push(if new.kiss.enabled { "KISS TNC (clients disconnected)" } else { "KISS TNC disabled" }.to_owned());
+    }
+
+    if old.airtime != new.airtime {
+        applied.push("Airtime limits".to_owned());
+    }
+
+    if old.capture != new.capture {
+        applied.push(if new.capture.enabled { "Capture (new file started)" } else { "Capture disabled" }.to_owned());
+    }
+
+    if old.ui_port != new.ui_port {
+        restart_needed.push("Web UI port");
+    }
+
+    if old.radio.csma != new.radio.csma {
+        applied.push("Channel access".to_owned());
+    }
+
+    let without_csma = |radio: &config::RadioConfig| config::RadioConfig { csma: Default::default(), ..radio.clone() };
+    if without_csma(&old.radio) != without_csma(&new.radio) {
+        restart_needed.push("Radio backend or wiring");
+    }
+
+    (applied, restart_needed)
 }
 
 async fn post_settings(
@@ -495,7 +1467,15 @@ async fn post_settings(
         Ok(c) => {
             match c.store() {
                 Ok(()) => {
-                    state.lock().unwrap().conf.clone_from(&c);
+                    let (applied, restart_needed) = {
+                        let st = state.lock().unwrap();
+                        // The frequency is only known with the RF radio
+                        let radio_tunable = st.frequency.is_some() && st.radio.alarm.is_none();
+                        let changes = describe_changes(&st.conf.borrow(), &c, radio_tunable);
+                        // Every subsystem subscribed to the configuration applies its part
+                        st.conf.send_replace(c.clone());
+                        changes
+                    };
 
                     (StatusCode::OK, SettingsAppliedTemplate {
                         title: "Settings",
@@ -504,6 +1484,8 @@ async fn post_settings(
                         ok: true,
                         error_message: "",
                         error_reason: "".to_owned(),
+                        applied,
+                        restart_needed,
                     })
                 }
                 Err(e) => {
@@ -514,6 +1496,8 @@ async fn post_settings(
                         ok: false,
                         error_message: "Failed to store config",
                         error_reason: e.to_string(),
+                        applied: vec![],
+                        restart_needed: vec![],
                     })
                 },
             }
@@ -521,12 +1505,172 @@ async fn post_settings(
         Err(e) => {
             (StatusCode::BAD_REQUEST, SettingsAppliedTemplate {
                         title: "Settings",
-                        conf: state.lock().unwrap().conf.clone(),
+                        conf: state.lock().unwrap().config(),
                         page: ActivePage::None,
                         ok: false,
                         error_message: "Error interpreting POST data",
                         error_reason: e.to_string(),
+                        applied: vec![],
+                        restart_needed: vec![],
                     })
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::{broadcast, mpsc, watch};
+    use crate::{radio, tx_queue, AppState};
+
+    fn export_range(uri: &str) -> (i64, i64) {
+        let uri : axum::http::Uri = uri.parse().unwrap();
+        let Query(range) = Query::<ExportRange>::try_from_uri(&uri).unwrap();
+        range.bounds().unwrap()
+    }
+
+    fn describe_frequency_change(radio_tunable: bool) -> Vec<String> {
+        let old = config::Config { freq: 430500, ..Default::default() };
+        let new = config::Config { freq: 430525, ..Default::default() };
+        describe_changes(&old, &new, radio_tunable).0
+    }
+
+    #[test]
+    fn frequency_change_with_radio() {
+        assert_eq!(describe_frequency_change(true), ["Radio tuned to 430525 kHz"]);
+    }
+
+    #[test]
+    fn frequency_change_without_radio() {
+        assert_eq!(describe_frequency_change(false), ["Frequency 430525 kHz saved, applied when the radio is available"]);
+    }
+
+    #[test]
+    fn export_range_bounds() {
+        assert_eq!(export_range("/api/export.pcap?from=100&to=200"), (100, 200));
+        assert_eq!(export_range("/api/export.pcap?from=100"), (100, i64::MAX));
+        assert_eq!(export_range("/api/export.pcap"), (0, i64::MAX));
+    }
+
+    #[test]
+    fn export_range_empty_bounds() {
+        // As sent by a form with both fields left blank
+        assert_eq!(export_range("/api/export.pcap?from=&to="), (0, i64::MAX));
+        assert_eq!(export_range("/api/export.pcap?from=&to=200"), (0, 200));
+    }
+
+    #[test]
+    fn export_range_invalid_bounds() {
+        let uri : axum::http::Uri = "/api/export.pcap?from=yesterday".parse().unwrap();
+        let Query(range) = Query::<ExportRange>::try_from_uri(&uri).unwrap();
+        assert!(range.bounds().is_err());
+    }
+
+    fn temporary_database_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("cats-radio-node-test-{}-{name}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn node_state(conf: config::Config, db: db::Database, transmit_queue: tx_queue::TxQueue) -> SharedState {
+        let csma = radio::Csma::new(&conf.radio.csma);
+        let (capture, _) = mpsc::channel(64);
+        Arc::new(Mutex::new(AppState {
+            conf: watch::Sender::new(conf),
+            db,
+            transmit_queue,
+            ws_broadcast: broadcast::Sender::new(2),
+            kiss_broadcast: broadcast::Sender::new(16),
+            capture,
+            start_time: chrono::Utc::now(),
+            frequency: None,
+            felinet: Default::default(),
+            radio: Default::default(),
+            csma,
+            airtime: Default::default(),
+            num_digipeated: 0,
+        }))
+    }
+
+    fn udp_config(rx_address: &str, tx_address: &str) -> config::Config {
+        let mut conf = config::Config {
+            callsign: "EX4MPLE".to_owned(),
+            ..Default::default()
+        };
+        conf.radio.backend = config::RadioBackendKind::Udp;
+        conf.radio.udp_rx_address = rx_address.to_owned();
+        conf.radio.udp_tx_address = tx_address.to_owned();
+        conf
+    }
+
+    async fn start_backend(conf: &config::Config, transmit_queue: tx_queue::TxQueue)
+        -> (mpsc::Receiver<(Vec<u8>, f64)>, mpsc::Receiver<radio::TxStatus>) {
+        let (receive_queue, receive) = mpsc::channel(16);
+        let (status_queue, status) = mpsc::channel(16);
+        let (frequency_control, frequency) = watch::channel(conf.freq);
+        let mut backend = radio::create_backend(config::RadioBackendKind::Udp, &conf.radio, receive_queue,
+            transmit_queue, status_queue, frequency, radio::Csma::new(&conf.radio.csma)).await.unwrap();
+        tokio::spawn(async move {
+            // The backend stops when the frequency can no longer change
+            let _frequency_control = frequency_control;
+            backend.process_forever().await
+        });
+        (receive, status)
+    }
+
+    #[tokio::test]
+    async fn sent_packet_arrives_unchanged() {
+        // A free port for the receiving node
+        let port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let receiver_address = format!("127.0.0.1:{port}");
+
+        let sender_conf = udp_config("127.0.0.1:0", &receiver_address);
+        let sender_queue = tx_queue::TxQueue::default();
+        let sender_db_path = temporary_database_path("sender");
+        let sender = node_state(sender_conf.clone(), db::Database::open(&sender_db_path).await, sender_queue.clone());
+        let (_, sender_status) = start_backend(&sender_conf, sender_queue).await;
+        tokio::spawn(transmit::run(sender.clone(), sender_status));
+
+        let receiver_conf = udp_config(&receiver_address, "127.0.0.1:9");
+        let receiver_db_path = temporary_database_path("receiver");
+        let mut receiver_db = db::Database::open(&receiver_db_path).await;
+        let (mut receiver_frames, _receiver_status) = start_backend(&receiver_conf, Default::default()).await;
+
+        // Over a kilobyte once encoded, as the comments typed in the chat can be
+        let payload = ApiSendPacket {
+            destinations: vec![ApiSendPacketDestination { callsign: "N0CALL".to_owned(), ssid: 1 }],
+            comment: Some("Over a kilobyte ".repeat(100)),
+        };
+        let data = build_packet(sender_conf.clone(), payload).unwrap();
+        assert!(data.len() > 1024);
+        let id = transmit::queue(&sender, data, db::TxSource::Ui).await.unwrap();
+
+        let (frame, rssi) = tokio::time::timeout(Duration::from_secs(5), receiver_frames.recv()).await
+            .expect("frame not received")
+            .unwrap();
+        // Stored as the receiving node stores every frame heard
+        let received_id = receiver_db.store_packet(&frame, &db::FrameMetadata::received(rssi, None)).await.unwrap();
+        let received = receiver_db.get_packet(received_id).await.unwrap().unwrap();
+
+        // The sending node records the frame once the radio reports it sent
+        let mut sender_db = sender.lock().unwrap().db.clone();
+        let transmission = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let t = sender_db.get_transmission(id).await.unwrap().unwrap();
+                if t.frame_id.is_some() {
+                    break t;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("transmission not recorded as sent");
+        let sent = sender_db.get_packet(transmission.frame_id.unwrap()).await.unwrap().unwrap();
+
+        assert_eq!(sent.direction, db::Direction::Transmitted);
+        assert_eq!(transmission.content, sent.content);
+        assert_eq!(received.content, sent.content);
+
+        let _ = std::fs::remove_file(sender_db_path);
+        let _ = std::fs::remove_file(receiver_db_path);
+    }
+}
@@ -53,7 +53,59 @@ function keep_alive() {
     setTimeout(keep_alive, 10000);
 }
 
+function heard_text(heard_via) {
+    if (heard_via.length == 0 || (heard_via.length == 1 && heard_via[0] == "direct")) {
+        return "";
+    }
+    else if (heard_via.length == 1) {
+        return `via ${heard_via[0]}`;
+    }
+    else {
+        return `heard ${heard_via.length} times via ${heard_via.join(", ")}`;
+    }
+}
+
+// Same as UIPacket::summary
+function message_summary(message) {
+    if (message.comment !== null) {
+        return message.comment;
+    }
+
+    let parts = [];
+    if (message.gps !== null) {
+        parts.push(`[position ${message.gps.latitude.toFixed(5)}, ${message.gps.longitude.toFixed(5)}]`);
+    }
+    if (message.node_info !== null) {
+        parts.push("[node info]");
+    }
+    if (message.destinations.length > 0) {
+        const destinations = message.destinations.map((d) => d.is_ack ? `${d.callsign}-${d.ssid} (ack ${d.ack_num})` : `${d.callsign}-${d.ssid}`);
+        parts.push(`[to ${destinations.join(", ")}]`);
+    }
+    if (parts.length == 0) {
+        parts.push("[no comment]");
+    }
+    return parts.join(" ");
+}
+
+function add_duplicate(message) {
+    const original = document.querySelector(`div.msg[data-id="${message.duplicate_of}"]`);
+    if (original === null) {
+        return;
+    }
+
+    let heard_via = original.dataset.heardVia.split(",").filter((v) => v !== "");
+    heard_via.push(...message.heard_via);
+    original.dataset.heardVia = heard_via.join(",");
+    original.querySelector("div.msg_heard").textContent = heard_text(heard_via);
+}
+
 function add_message(message) {
+    if (message.duplicate_of !== null) {
+        add_duplicate(message);
+        return;
+    }
+
     let template = document.getElementById('message_template');
     let clon = template.content.cloneNode(true);
 
@@ -65,7 +117,22 @@ function add_message(message) {
     msg_from.textContent = `${message.from_callsign}-${message.from_ssid}`;
 
     const msg_comment = clon.querySelector("div.msg_comment");
-    msg_comment.textContent = message.comment;
+    msg_comment.textContent = message_summary(message);
+
+    const msg = clon.querySelector("div.msg");
+    msg.dataset.id = message.id;
+    msg.dataset.heardVia = message.heard_via.join(",");
+
+    const msg_heard = clon.querySelector("div.msg_heard");
+    msg_heard.textContent = heard_text(message.heard_via);
+
+    const msg_rssi = clon.querySelector("div.msg_rssi");
+    if (message.rssi !== null) {
+        msg_rssi.textContent = `${message.rssi.toFixed(1)} dBm`;
+    }
+    else {
+        msg_rssi.textContent = "";
+    }
 
     const messagelist = document.getElementById('messagelist');
     messagelist.appendChild(clon);